@@ -1,20 +1,50 @@
 use std::{
     collections::{HashMap, HashSet, VecDeque},
+    fs,
+    path::Path,
     sync::Arc,
 };
 
-use log::{error, info};
+use futures::{stream, StreamExt};
+use log::{error, info, warn};
 use rustmix::{error::*, *};
 
-use crate::{common::*, service::*};
+use crate::{
+    api::{ApiMessage, ApiNewMessage},
+    archive::Archive,
+    common::*,
+    error::{is_not_found, YammerError},
+    export::Exporter,
+    journal::{DeleteFilters, Journal},
+    manifest,
+    oauth::{self, OAuthSettings},
+    output::{self, OutputWriter},
+    service::*,
+};
 
 pub struct ActionHandler {
     service: Arc<Service>,
+    output: Arc<OutputWriter>,
+    concurrency: usize,
+    oauth: OAuthSettings,
+    profile: Option<String>,
 }
 
 impl ActionHandler {
-    pub fn new(service: Arc<Service>) -> Self {
-        Self { service }
+    pub fn new(
+        service: Arc<Service>,
+        output: Arc<OutputWriter>,
+        concurrency: usize,
+        oauth: OAuthSettings,
+        profile: Option<String>,
+    ) -> Self {
+        Self {
+            service,
+            output,
+            concurrency: concurrency.max(1),
+            oauth,
+            profile,
+        }
     }
 
     pub async fn process(&self, token: &String, action: &YammerAction) -> Result<()> {
@@ -22,7 +52,7 @@ impl ActionHandler {
         match action {
             YammerAction::User { user_id } => {
                 let user = self.service.get_user_info(&token, *user_id).await?;
-                output::print_user(&user);
+                self.output.write(&user)?;
                 return Ok(());
             }
             YammerAction::Users { group_id } => {
@@ -36,47 +66,223 @@ impl ActionHandler {
                 email,
                 all,
             } => {
-                let user_id = if let Some(email) = email {
-                    match self.service.get_user_id(&token, email).await {
-                        Ok(it) => Some(it),
-                        Err(e) => {
-                            error!("{}", e.get_message());
-                            return Ok(());
-                        }
-                    }
-                } else {
-                    None
-                };
+                let user_id = self.get_user_id(&token, email.as_deref()).await?;
                 let count = self
                     .list(&token, *group_id, *thread_id, user_id, *all)
                     .await?;
                 info!("Listed {} messages", count);
                 return Ok(());
             }
+            YammerAction::Search { query } => {
+                let count = self.service.search(&token, query, &self.output).await?;
+                info!("Found {} results", count);
+                return Ok(());
+            }
             YammerAction::Delete {
                 group_id,
                 thread_id,
                 email,
                 exclude,
+                dry_run,
+                plan_out,
+                resume,
+                archive,
             } => {
-                let user_id = if let Some(email) = &*email {
-                    match self.service.get_user_id(&token, email).await {
-                        Ok(it) => Some(it),
-                        Err(e) => {
-                            error!("{}", e.get_message());
-                            return Ok(());
-                        }
-                    }
-                } else {
-                    None
-                };
+                let user_id = self.get_user_id(&token, email.as_deref()).await?;
                 let exclude = match exclude {
                     Some(exclude) => parse_excludes(exclude),
                     None => HashSet::new(),
                 };
+
+                if *dry_run || plan_out.is_some() {
+                    let mut ctx = DeleteContext::new(self.output.clone());
+                    ctx.plan = Some(Vec::new());
+                    ctx.concurrency = self.concurrency;
+                    let count = self
+                        .service
+                        .delete(&token, *group_id, *thread_id, user_id, &exclude, &mut ctx)
+                        .await?;
+                    let plan = ctx.plan.unwrap_or_default();
+                    info!(
+                        "Dry run: {} messages would be deleted, {} would be kept",
+                        count,
+                        plan.len() as u64 - count
+                    );
+
+                    if let Some(plan_out) = plan_out {
+                        DeletePlan::new(user_id, &plan).save(plan_out)?;
+                        info!("Deletion plan saved to '{}'", plan_out.display());
+                    }
+
+                    return Ok(());
+                }
+
+                let filters = DeleteFilters::new(*group_id, *thread_id, user_id, &exclude);
+                let journal = match resume {
+                    Some(path) => Journal::resume(path, &filters)?,
+                    None => Journal::create(&filters)?,
+                };
+                let archive = match archive {
+                    Some(path) => Some(Archive::open(path)?),
+                    None => None,
+                };
+                let mut ctx = DeleteContext::new(self.output.clone());
+                ctx.concurrency = self.concurrency;
+                ctx.journal = Some(journal);
+                ctx.archive = archive;
+                let result = self
+                    .service
+                    .delete(&token, *group_id, *thread_id, user_id, &exclude, &mut ctx)
+                    .await;
+
+                if let (Err(_), Some(journal)) = (&result, &ctx.journal) {
+                    info!(
+                        "Run the same command with --resume '{}' to continue",
+                        journal.path().display()
+                    );
+                }
+
+                let count = result?;
+                info!("Deleted {} messages", count);
+                return Ok(());
+            }
+            YammerAction::Redact {
+                group_id,
+                thread_id,
+                email,
+                placeholder,
+                dry_run,
+            } => {
+                let user_id = self.service.get_user_id(&token, email).await?;
+                let mut ctx = RedactContext::new(self.output.clone(), placeholder);
+                ctx.dry_run = *dry_run;
+                let count = self
+                    .service
+                    .redact(&token, *group_id, *thread_id, user_id, &mut ctx)
+                    .await?;
+
+                if *dry_run {
+                    info!("Dry run: {} messages would be redacted", count);
+                } else {
+                    info!("Redacted {} messages", count);
+                }
+
+                return Ok(());
+            }
+            YammerAction::Like { message_id } => {
+                self.service.like_message(&token, *message_id).await?;
+                return Ok(());
+            }
+            YammerAction::Unlike { message_id } => {
+                if !self.service.unlike_message(&token, *message_id).await? {
+                    return Err(YammerError::Partial { done: 0, failed: 1 }.into());
+                }
+
+                return Ok(());
+            }
+            YammerAction::UnlikeAll { email, dry_run } => {
+                let user_id = self.service.get_user_id(&token, email).await?;
+                let current = self.service.get_current_user(&token).await?;
+
+                // Unliking always acts as the token's user, so another user's likes cannot be removed
+                if current.id != user_id {
+                    return Err(YammerError::NotCurrentUser {
+                        email: email.clone(),
+                    }
+                    .into());
+                }
+
                 let count = self
                     .service
-                    .delete(&token, *group_id, *thread_id, user_id, &exclude)
+                    .unlike_all(&token, user_id, *dry_run, &self.output)
+                    .await?;
+
+                if *dry_run {
+                    info!("Dry run: {} likes would be removed", count);
+                } else {
+                    info!("Removed {} likes", count);
+                }
+
+                return Ok(());
+            }
+            YammerAction::Export {
+                dir,
+                group_id,
+                email,
+                attachments,
+            } => {
+                let user_id = self.get_user_id(&token, email.as_deref()).await?;
+                let count = self
+                    .export(&token, dir, *group_id, user_id, *attachments)
+                    .await?;
+                info!("Exported {} messages", count);
+                return Ok(());
+            }
+            YammerAction::Login {
+                device,
+                client_id,
+                client_secret,
+                authorize_url,
+                token_url,
+                device_code_url,
+                scope,
+                port,
+            } => {
+                let settings = self.oauth.merge(&OAuthSettings {
+                    client_id: client_id.clone(),
+                    client_secret: client_secret.clone(),
+                    authorize_url: authorize_url.clone(),
+                    token_url: token_url.clone(),
+                    device_code_url: device_code_url.clone(),
+                    scope: scope.clone(),
+                });
+                let path = oauth::login(&settings, *device, *port, self.profile.as_deref()).await?;
+                info!("Signed in. Tokens saved to '{}'", path.display());
+                return Ok(());
+            }
+            YammerAction::Post {
+                group_id,
+                replied_to_id,
+                to,
+                body,
+                body_file,
+            } => {
+                let message = ApiNewMessage {
+                    body: read_body(body.as_deref(), body_file.as_deref())?,
+                    group_id: *group_id,
+                    replied_to_id: *replied_to_id,
+                    direct_to_user_ids: to.clone(),
+                };
+                let message = self.service.post_message(&token, &message).await?;
+                self.output.write(&message)?;
+                return Ok(());
+            }
+            YammerAction::PostBatch {
+                manifest,
+                template,
+                dry_run,
+            } => {
+                let count = self
+                    .post_batch(&token, manifest, template.as_deref(), *dry_run)
+                    .await?;
+
+                if *dry_run {
+                    info!("Dry run: {} messages would be posted", count);
+                } else {
+                    info!("Posted {} messages", count);
+                }
+
+                return Ok(());
+            }
+            YammerAction::ApplyPlan { plan, archive } => {
+                let plan = DeletePlan::load(plan)?;
+                let mut archive = match archive {
+                    Some(path) => Some(Archive::open(path)?),
+                    None => None,
+                };
+                let count = self
+                    .service
+                    .apply_plan(&token, &plan, archive.as_mut(), &self.output)
                     .await?;
                 info!("Deleted {} messages", count);
                 return Ok(());
@@ -137,32 +343,64 @@ impl ActionHandler {
                 )
                 .await?;
 
+            if all {
+                last_message_id = messages.back().map(|e| e.id).or(last_message_id);
+                // Threads are fetched concurrently but printed in the order the
+                // messages were listed.
+                let mut threads = stream::iter(messages.drain(..))
+                    .map(|message| {
+                        let thread_id = message.thread_id();
+                        async move {
+                            let mut thread = VecDeque::new();
+                            let result = self
+                                .service
+                                .get_messages_in_thread(&mut thread, token, thread_id, user_id)
+                                .await;
+                            (thread_id, result.map(|_| thread))
+                        }
+                    })
+                    .buffered(self.concurrency);
+
+                while let Some((thread_id, result)) = threads.next().await {
+                    count += match result {
+                        Ok(thread) => {
+                            self.write_thread(
+                                token,
+                                thread_id,
+                                user_id,
+                                thread,
+                                &mut users,
+                                &mut groups,
+                            )
+                            .await?
+                        }
+                        Err(e) if is_not_found(e.as_ref()) => {
+                            warn!("Thread '{}' is not found", thread_id);
+                            0
+                        }
+                        Err(e) => return Err(e),
+                    };
+                }
+
+                continue;
+            }
+
             // using pop_front to print the messages in order (newest/child to oldest/parent)
             while let Some(message) = messages.pop_front() {
-                last_message_id = message["id"].as_u64();
-
-                if all {
-                    let thread_id = message["thread_id"].as_u64().unwrap();
-                    count += self
-                        .list_thread(token, thread_id, user_id, &mut users, &mut groups)
+                last_message_id = Some(message.id);
+
+                let sender_id = message.sender_id.unwrap_or(0);
+                self.add_user(&mut users, token, sender_id).await?;
+                let group_id = message.group_id.unwrap_or(0);
+                if !groups.contains_key(&group_id) && uid != sender_id {
+                    self.service
+                        .get_user_groups(&mut groups, token, sender_id)
                         .await?;
-                } else {
-                    let sender_id = message["sender_id"].as_u64().unwrap();
-                    if !users.contains_key(&sender_id) {
-                        let user = self.service.get_user_info(&token, sender_id).await?;
-                        users.insert(sender_id, user);
-                    }
-                    let group_id = message["group_id"].as_u64().unwrap();
-                    if !groups.contains_key(&group_id) && uid != sender_id {
-                        self.service
-                            .get_user_groups(&mut groups, token, sender_id)
-                            .await?;
-                    }
-
-                    let message = YammerMessage::from_json(&message, Some(&users), Some(&groups));
-                    output::print_message(&message);
-                    count += 1;
                 }
+
+                let message = YammerMessage::from_api(&message, Some(&users), Some(&groups));
+                self.output.write(&message)?;
+                count += 1;
             }
         }
 
@@ -182,30 +420,40 @@ impl ActionHandler {
         self.service
             .get_messages_in_thread(&mut messages, token, thread_id, user_id)
             .await?;
+        self.write_thread(token, thread_id, user_id, messages, users, groups)
+            .await
+    }
 
+    /// Prints the messages of a thread as trees of replies.
+    async fn write_thread(
+        &self,
+        token: &str,
+        thread_id: u64,
+        user_id: Option<u64>,
+        mut messages: VecDeque<ApiMessage>,
+        users: &mut HashMap<u64, YammerUser>,
+        groups: &mut HashMap<u64, YammerGroup>,
+    ) -> Result<u64> {
         if messages.is_empty() {
             return Ok(0);
         }
 
         let count = messages.len() as u64;
-        println!("Messages for thread {}", thread_id);
+        info!("Messages for thread {}", thread_id);
         let mut roots: HashMap<u64, YammerMessage> = HashMap::new();
         let uid = user_id.unwrap_or(0);
 
         while let Some(message) = messages.pop_back() {
-            let sender_id = message["sender_id"].as_u64().unwrap();
-            if !users.contains_key(&sender_id) {
-                let user = self.service.get_user_info(&token, sender_id).await?;
-                users.insert(sender_id, user);
-            }
-            let group_id = message["group_id"].as_u64().unwrap();
+            let sender_id = message.sender_id.unwrap_or(0);
+            self.add_user(users, token, sender_id).await?;
+            let group_id = message.group_id.unwrap_or(0);
             if !groups.contains_key(&group_id) && uid != sender_id {
                 self.service
                     .get_user_groups(groups, token, sender_id)
                     .await?;
             }
 
-            let message = YammerMessage::from_json(&message, Some(&users), Some(&groups));
+            let message = YammerMessage::from_api(&message, Some(&users), Some(&groups));
             let replied_to_id = message.replied_to_id.unwrap_or(thread_id);
 
             if let Some(root) = roots.get_mut(&replied_to_id) {
@@ -225,7 +473,7 @@ impl ActionHandler {
         }
 
         for message in roots.values() {
-            output::print_message(message)
+            self.output.write(message)?;
         }
 
         return Ok(count);
@@ -264,6 +512,281 @@ impl ActionHandler {
         }
     }
 
+    async fn export(
+        &self,
+        token: &str,
+        dir: &Path,
+        group_id: Option<u64>,
+        user_id: Option<u64>,
+        attachments: bool,
+    ) -> Result<u64> {
+        let mut exporter = Exporter::create(dir)?;
+        let mut users = HashMap::new();
+        let mut groups = HashMap::new();
+
+        if let Some(user_id) = user_id {
+            self.service
+                .get_user_info(&token, user_id)
+                .await
+                .map(|user| users.insert(user_id, user))
+                .ok();
+            self.service
+                .get_user_groups(&mut groups, token, user_id)
+                .await?;
+        }
+
+        let mut messages = VecDeque::new();
+        let mut has_more = true;
+        let mut last_message_id = None;
+        let mut count = 0u64;
+        let mut failed = 0u64;
+
+        if let Some(group_id) = group_id {
+            info!("Fetching messages for export in group '{}'", group_id);
+        } else {
+            info!("Fetching messages for export");
+        }
+
+        while has_more {
+            has_more = if group_id.is_some() || user_id.is_some() {
+                self.service
+                    .get_messages(&mut messages, token, group_id, user_id, last_message_id)
+                    .await?
+            } else {
+                self.service
+                    .get_feed_messages(&mut messages, token, last_message_id)
+                    .await?
+            };
+
+            while let Some(message) = messages.pop_front() {
+                last_message_id = Some(message.id);
+                let thread_id = message.thread_id();
+
+                if exporter.has_thread(thread_id) {
+                    continue;
+                }
+
+                let (exported, failed_attachments) = self
+                    .export_thread(
+                        token,
+                        thread_id,
+                        attachments,
+                        &mut exporter,
+                        &mut users,
+                        &mut groups,
+                    )
+                    .await?;
+                count += exported;
+                failed += failed_attachments;
+            }
+        }
+
+        exporter.write_users(&users)?;
+        exporter.write_groups(&groups)?;
+
+        if failed > 0 {
+            warn!("{} attachments could not be downloaded", failed);
+        }
+
+        return YammerError::partial(count, failed);
+    }
+
+    /// Returns the number of exported messages and of the attachments that
+    /// could not be downloaded.
+    async fn export_thread(
+        &self,
+        token: &str,
+        thread_id: u64,
+        attachments: bool,
+        exporter: &mut Exporter,
+        users: &mut HashMap<u64, YammerUser>,
+        groups: &mut HashMap<u64, YammerGroup>,
+    ) -> Result<(u64, u64)> {
+        info!("Exporting thread {}", thread_id);
+        let mut messages = Vec::new();
+        let mut has_more = true;
+
+        while has_more {
+            let last_message_id = messages.last().map(|e: &ApiMessage| e.id);
+            has_more = self
+                .service
+                .get_thread_page(&mut messages, token, thread_id, last_message_id)
+                .await?;
+        }
+
+        messages.retain(|e| e.is_from_user());
+
+        for message in &messages {
+            let sender_id = message.sender_id.unwrap_or(0);
+            if !users.contains_key(&sender_id) {
+                match self.service.get_user_info(&token, sender_id).await {
+                    Ok(user) => {
+                        users.insert(sender_id, user);
+                    }
+                    Err(e) => warn!("Could not fetch user '{}': {}", sender_id, e.get_message()),
+                }
+            }
+            let group_id = message.group_id.unwrap_or(0);
+            if group_id != 0 && !groups.contains_key(&group_id) {
+                self.service
+                    .get_user_groups(groups, token, sender_id)
+                    .await?;
+            }
+        }
+
+        exporter.write_thread(thread_id, &messages, users, groups)?;
+        let mut failed = 0u64;
+
+        if attachments {
+            for message in &messages {
+                for attachment in &message.attachments {
+                    let url = match &attachment.download_url {
+                        Some(it) => it,
+                        None => continue,
+                    };
+                    let bytes = match self.service.download(token, url).await {
+                        Ok(it) => it,
+                        Err(e) => {
+                            error!(
+                                "Could not download attachment '{}' of message '{}': {}",
+                                url,
+                                message.id,
+                                e.get_message()
+                            );
+                            failed += 1;
+                            continue;
+                        }
+                    };
+                    let path = exporter.write_attachment(message.id, attachment, &bytes)?;
+                    info!("Saved attachment '{}'", path.display());
+                }
+            }
+        }
+
+        return Ok((messages.len() as u64, failed));
+    }
+
+    /// Posts the rows of the manifest one by one and reports every row. A row
+    /// that fails does not stop the batch.
+    async fn post_batch(
+        &self,
+        token: &str,
+        path: &Path,
+        template: Option<&Path>,
+        dry_run: bool,
+    ) -> Result<u64> {
+        let rows = manifest::load(path)?;
+        let template = match template {
+            Some(path) => Some(fs::read_to_string(path)?),
+            None => None,
+        };
+        info!("Posting {} rows from '{}'", rows.len(), path.display());
+        let mut count = 0u64;
+        let mut failed = 0u64;
+
+        for (index, row) in rows.iter().enumerate() {
+            let mut report = PostReport::new(index + 1);
+
+            match self
+                .post_row(token, row, template.as_deref(), dry_run, &mut report)
+                .await
+            {
+                Ok(_) => count += 1,
+                Err(e) => {
+                    warn!("Row {} failed: {}", report.row, e.get_message());
+                    report.status = PostStatus::Failed;
+                    report.error = Some(e.get_message());
+                    failed += 1;
+                }
+            }
+
+            self.output.write(&report)?;
+        }
+
+        return YammerError::partial(count, failed);
+    }
+
+    async fn post_row(
+        &self,
+        token: &str,
+        row: &manifest::Row,
+        template: Option<&str>,
+        dry_run: bool,
+        report: &mut PostReport,
+    ) -> Result<()> {
+        let row = manifest::parse_row(row, template)?;
+        report.group_id = row.group_id;
+        report.replied_to_id = row.replied_to_id;
+        report.body = row.body.clone();
+
+        for recipient in &row.to {
+            let id = match recipient.parse() {
+                Ok(it) => it,
+                // A dry run makes no request, so the emails are only reported
+                Err(_) if dry_run => {
+                    report.unresolved.push(recipient.clone());
+                    continue;
+                }
+                Err(_) => self.service.get_user_id(token, recipient).await?,
+            };
+            report.to.push(id);
+        }
+
+        if dry_run {
+            report.status = PostStatus::Planned;
+            return Ok(());
+        }
+
+        let message = ApiNewMessage {
+            body: row.body,
+            group_id: row.group_id,
+            replied_to_id: row.replied_to_id,
+            direct_to_user_ids: report.to.clone(),
+        };
+        let message = self.service.post_message(token, &message).await?;
+        report.status = PostStatus::Posted;
+        report.message_id = Some(message.id);
+        return Ok(());
+    }
+
+    async fn get_user_id(&self, token: &str, email: Option<&str>) -> Result<Option<u64>> {
+        let email = match email {
+            Some(it) => it,
+            None => return Ok(None),
+        };
+
+        match self.service.get_user_id(token, email).await {
+            Ok(it) => Ok(Some(it)),
+            Err(e) => {
+                error!("Could not find user '{}': {}", email, e.get_message());
+                Err(e)
+            }
+        }
+    }
+
+    /// Adds the sender to `users` unless it is already there. Senders that no
+    /// longer exist are left out so their messages are still listed.
+    async fn add_user(
+        &self,
+        users: &mut HashMap<u64, YammerUser>,
+        token: &str,
+        user_id: u64,
+    ) -> Result<()> {
+        if users.contains_key(&user_id) {
+            return Ok(());
+        }
+
+        match self.service.get_user_info(token, user_id).await {
+            Ok(user) => {
+                users.insert(user_id, user);
+            }
+            Err(e) if is_not_found(e.as_ref()) => warn!("User '{}' is not found", user_id),
+            Err(e) => return Err(e),
+        }
+
+        Ok(())
+    }
+
     async fn list_users(&self, token: &str, group_id: Option<u64>) -> Result<u64> {
         let mut users = HashMap::new();
         let mut page = 1;
@@ -283,7 +806,7 @@ impl ActionHandler {
                 count += users.len() as u64;
 
                 for user in users.values() {
-                    output::print_user(user);
+                    self.output.write(user)?;
                 }
 
                 users.clear();
@@ -299,7 +822,7 @@ impl ActionHandler {
                 count += users.len() as u64;
 
                 for user in users.values() {
-                    output::print_user(user);
+                    self.output.write(user)?;
                 }
 
                 users.clear();
@@ -0,0 +1,197 @@
+//! Typed requests and responses of the Yammer REST API. Every response field that Yammer may omit or
+//! send as null is optional, and the fields this crate does not use are kept
+//! in `extra` so a model serializes back to the JSON it was parsed from.
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiUser {
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub name: Option<String>,
+    pub full_name: Option<String>,
+    pub email: Option<String>,
+    pub network_id: Option<u64>,
+    pub state: Option<String>,
+    pub job_title: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiGroup {
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub name: Option<String>,
+    pub full_name: Option<String>,
+    pub privacy: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiBody {
+    pub plain: Option<String>,
+    pub parsed: Option<String>,
+    pub rich: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl ApiBody {
+    /// The richest representation available.
+    pub fn text(&self) -> &str {
+        self.rich
+            .as_deref()
+            .or(self.parsed.as_deref())
+            .or(self.plain.as_deref())
+            .unwrap_or("")
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiLiker {
+    pub user_id: Option<u64>,
+    pub full_name: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiLikedBy {
+    #[serde(default)]
+    pub count: u64,
+    #[serde(default)]
+    pub names: Vec<ApiLiker>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiAttachment {
+    pub id: Option<u64>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub name: Option<String>,
+    pub download_url: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiMessage {
+    pub id: u64,
+    pub sender_id: Option<u64>,
+    pub sender_type: Option<String>,
+    pub replied_to_id: Option<u64>,
+    pub network_id: Option<u64>,
+    pub group_id: Option<u64>,
+    pub thread_id: Option<u64>,
+    pub privacy: Option<String>,
+    pub created_at: Option<String>,
+    pub body: Option<ApiBody>,
+    pub liked_by: Option<ApiLikedBy>,
+    #[serde(default)]
+    pub attachments: Vec<ApiAttachment>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl ApiMessage {
+    pub fn is_from_user(&self) -> bool {
+        self.sender_type.as_deref() == Some("user")
+    }
+
+    pub fn thread_id(&self) -> u64 {
+        self.thread_id.unwrap_or(self.id)
+    }
+
+    pub fn likes(&self) -> u64 {
+        self.liked_by.as_ref().map(|e| e.count).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiMeta {
+    #[serde(default)]
+    pub older_available: bool,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// An entry of the `references` array. References are users, groups,
+/// threads, topics and so on; only the common fields are typed.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiReference {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub id: Option<u64>,
+    pub name: Option<String>,
+    pub full_name: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The body of `POST messages.json`: a message in a group, a reply, or a
+/// private message to the listed users.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ApiNewMessage {
+    pub body: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replied_to_id: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub direct_to_user_ids: Vec<u64>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiMessages {
+    #[serde(default)]
+    pub messages: Vec<ApiMessage>,
+    #[serde(default)]
+    pub meta: ApiMeta,
+    #[serde(default)]
+    pub references: Vec<ApiReference>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiTopic {
+    pub id: u64,
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The total number of matches of every result type of a search.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiSearchCount {
+    #[serde(default)]
+    pub messages: u64,
+    #[serde(default)]
+    pub users: u64,
+    #[serde(default)]
+    pub groups: u64,
+    #[serde(default)]
+    pub topics: u64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// One page of `search.json`. Every result type is paged on its own, so a
+/// page may hold more of one type than of another.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiSearch {
+    #[serde(default)]
+    pub count: ApiSearchCount,
+    #[serde(default)]
+    pub messages: ApiMessages,
+    #[serde(default)]
+    pub users: Vec<ApiUser>,
+    #[serde(default)]
+    pub groups: Vec<ApiGroup>,
+    #[serde(default)]
+    pub topics: Vec<ApiTopic>,
+}
@@ -0,0 +1,60 @@
+use chrono::Local;
+use log::info;
+use rustmix::Result;
+use serde::Serialize;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use crate::api::ApiMessage;
+
+#[derive(Debug, Serialize)]
+struct ArchiveRecord<'a> {
+    archived_at: String,
+    thread_id: u64,
+    message: &'a ApiMessage,
+    replies: Vec<&'a ApiMessage>,
+}
+
+/// An append-only JSON-lines file holding the raw JSON of every message
+/// before it gets deleted. A record is synced to disk before `write` returns
+/// so a message is never deleted without its backup.
+#[derive(Debug)]
+pub struct Archive {
+    file: File,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        info!("Archiving deleted messages to '{}'", path.display());
+        Ok(Self { file })
+    }
+
+    pub fn write(
+        &mut self,
+        thread_id: u64,
+        message: &ApiMessage,
+        replies: Vec<&ApiMessage>,
+    ) -> Result<()> {
+        let record = ArchiveRecord {
+            archived_at: Local::now().to_rfc3339(),
+            thread_id,
+            message,
+            replies,
+        };
+        let line = serde_json::to_string(&record)?;
+        writeln!(self.file, "{}", line)?;
+        self.file.flush()?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use chrono::Local;
 use clap::{command, ArgGroup, Parser, Subcommand};
 use lazy_static::lazy_static;
 use reqwest_cookie_store::CookieStoreRwLock;
@@ -12,12 +13,20 @@ use rustmix::{
     AppInfo, Result,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::{to_string_pretty, Value};
+use serde_json::to_string_pretty;
 use std::{
     collections::{HashMap, HashSet},
-    path::PathBuf,
+    fs, io,
+    path::{Path, PathBuf},
     sync::Arc,
-    time::{Duration, Instant},
+    time::Duration,
+};
+
+use crate::{
+    api::{ApiGroup, ApiMessage, ApiTopic, ApiUser},
+    error::YammerError,
+    output::OutputFormat,
+    rate_limit::RateLimit,
 };
 
 #[cfg(debug_assertions)]
@@ -26,6 +35,7 @@ pub const TIMEOUT: u64 = 30;
 pub const TIMEOUT: u64 = 5;
 
 const ARGSGRP_GROUP_OR_THREAD: &str = "EitherGroupOrThread";
+const ARGSGRP_POST_TARGET: &str = "PostTarget";
 
 lazy_static! {
     pub static ref APP_INFO: Arc<AppInfo<'static>> = Arc::new(AppInfo::new(
@@ -48,12 +58,67 @@ lazy_static! {
     about = env!("CARGO_PKG_DESCRIPTION")
 )]
 pub struct Args {
-    /// The Yammer application token.
-    #[arg(short = 'k', long, required = true)]
-    pub token: String,
+    /// The Yammer application token. Prefer --token-file, YAMMER_TOKEN or the config file
+    /// so the token stays out of the shell history.
+    #[arg(short = 'k', long, global = true)]
+    pub token: Option<String>,
+    /// A file holding the Yammer application token.
+    #[arg(long, global = true, conflicts_with = "token")]
+    pub token_file: Option<PathBuf>,
+    /// A refresh token to renew the token when it expires. Needs oauth.client_id in the config file.
+    #[arg(long, global = true)]
+    pub refresh_token: Option<String>,
+    /// The config file. Defaults to ~/.config/yamutil/config.toml.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// The API base URL, e.g. a local mock or a recording proxy. Also read from YAMMER_BASE_URL.
+    #[arg(long, global = true)]
+    pub base_url: Option<String>,
+    /// The profile of the config file to use. Defaults to default_profile in the config file.
+    #[arg(short = 'p', long, global = true)]
+    pub profile: Option<String>,
+    /// Save every HTTP request and response to this directory, with the credentials redacted.
+    #[arg(long, global = true, conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+    /// Answer the HTTP requests from a directory written by --record instead of the network.
+    #[arg(long, global = true)]
+    pub replay: Option<PathBuf>,
     /// Enable debug mode. The build must be a debug build.
     #[arg(short, long)]
     pub debug: bool,
+    /// The number of times a request is retried after a 429, a 502, 503 or 504, or a timeout.
+    #[arg(long, default_value_t = 5, global = true)]
+    pub max_retries: u32,
+    /// The delay of the first retry, e.g. 500ms or 2s. It doubles on every retry.
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration, global = true)]
+    pub retry_delay: Duration,
+    /// The longest delay between two retries, including the one requested by Retry-After.
+    #[arg(long, default_value = "60s", value_parser = humantime::parse_duration, global = true)]
+    pub max_retry_delay: Duration,
+    /// The rate limit of reads, as <requests>/<period>. Defaults to 10/10s.
+    #[arg(long, global = true)]
+    pub read_rate: Option<RateLimit>,
+    /// The rate limit of deletes and other writes, as <requests>/<period>. Defaults to 10/10s.
+    #[arg(long, global = true)]
+    pub write_rate: Option<RateLimit>,
+    /// The rate limit of listing messages, as <requests>/<period>. Defaults to 10/30s.
+    #[arg(long, global = true)]
+    pub messages_rate: Option<RateLimit>,
+    /// The rate limit of autocomplete requests, as <requests>/<period>. Defaults to 10/10s.
+    #[arg(long, global = true)]
+    pub autocomplete_rate: Option<RateLimit>,
+    /// Keep the rate limits fixed instead of slowing down after 429 responses.
+    #[arg(long, global = true)]
+    pub fixed_rate: bool,
+    /// The number of threads fetched and processed at the same time by list --all and delete.
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..=64), global = true)]
+    pub concurrency: u16,
+    /// The output format of the results. Defaults to the output file's extension or json.
+    #[arg(short, long, value_enum, global = true)]
+    pub format: Option<OutputFormat>,
+    /// Write the results to a file instead of stdout.
+    #[arg(short, long, global = true)]
+    pub output: Option<PathBuf>,
     /// The action to take on Yammer user's posts.
     #[command(subcommand)]
     pub action: YammerAction,
@@ -89,6 +154,12 @@ pub enum YammerAction {
         #[arg(short, long)]
         all: bool,
     },
+    /// Search messages, users, groups and topics.
+    Search {
+        /// The text to search for.
+        #[arg(short, long)]
+        query: String,
+    },
     /// Delete messages.
     #[command(group(ArgGroup::new(ARGSGRP_GROUP_OR_THREAD).args(&["group_id", "thread_id"])))]
     Delete {
@@ -104,41 +175,139 @@ pub enum YammerAction {
         /// Message IDs to exclude from deletion.
         #[arg(short = 'x', long)]
         exclude: Option<String>,
+        /// Print the deletion plan without deleting any message.
+        #[arg(long)]
+        dry_run: bool,
+        /// Write the deletion plan to a file without deleting any message.
+        #[arg(long)]
+        plan_out: Option<PathBuf>,
+        /// Resume an interrupted deletion from its journal file. The filters must match the interrupted run.
+        #[arg(long, conflicts_with_all = ["dry_run", "plan_out"])]
+        resume: Option<PathBuf>,
+        /// Append the raw JSON of every message to this file before deleting it.
+        #[arg(long, conflicts_with_all = ["dry_run", "plan_out"])]
+        archive: Option<PathBuf>,
+    },
+    /// Replace the bodies of the user's messages with a placeholder, for the threads delete
+    /// cannot remove because of other people's likes or replies.
+    #[command(group(ArgGroup::new(ARGSGRP_GROUP_OR_THREAD).args(&["group_id", "thread_id"])))]
+    Redact {
+        /// The message group id. If no group id is provided, all the user's threads are checked.
+        #[arg(short, long, group = ARGSGRP_GROUP_OR_THREAD)]
+        group_id: Option<u64>,
+        /// The message thread id.
+        #[arg(short, long, group = ARGSGRP_GROUP_OR_THREAD)]
+        thread_id: Option<u64>,
+        /// The email of the user whose messages are redacted.
+        #[arg(short, long)]
+        email: String,
+        /// The text that replaces the message bodies.
+        #[arg(long, default_value = "[redacted]")]
+        placeholder: String,
+        /// Print the messages that would be redacted without changing them.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Like a message.
+    Like {
+        /// The message id.
+        message_id: u64,
+    },
+    /// Remove the like of a message.
+    Unlike {
+        /// The message id.
+        message_id: u64,
+    },
+    /// Remove all the likes of a user. The token must belong to that user.
+    UnlikeAll {
+        /// The email of the user whose likes are removed.
+        #[arg(short, long)]
+        email: String,
+        /// Print the liked messages without removing the likes.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Export messages, users and groups to a directory.
+    Export {
+        /// The output directory.
+        #[arg(long)]
+        dir: PathBuf,
+        /// The message group id. If no group id or email is provided, the whole feed will be exported.
+        #[arg(short, long)]
+        group_id: Option<u64>,
+        /// The user email to filter posts.
+        #[arg(short, long)]
+        email: Option<String>,
+        /// Download the messages' attachments.
+        #[arg(short, long)]
+        attachments: bool,
+    },
+    /// Sign in with OAuth and save the tokens for the other actions.
+    Login {
+        /// Use the device code flow for hosts without a browser.
+        #[arg(long)]
+        device: bool,
+        /// The OAuth client (application) id. Defaults to oauth.client_id in the config file.
+        #[arg(long)]
+        client_id: Option<String>,
+        /// The OAuth client secret, for confidential clients.
+        #[arg(long)]
+        client_secret: Option<String>,
+        /// The authorization endpoint.
+        #[arg(long)]
+        authorize_url: Option<String>,
+        /// The token endpoint.
+        #[arg(long)]
+        token_url: Option<String>,
+        /// The device authorization endpoint.
+        #[arg(long)]
+        device_code_url: Option<String>,
+        /// The space separated scopes to request.
+        #[arg(long)]
+        scope: Option<String>,
+        /// The port of the loopback redirect listener. 0 picks a free port.
+        #[arg(long, default_value_t = 0)]
+        port: u16,
+    },
+    /// Post a message to a group, as a reply, or as a private message.
+    #[command(group(ArgGroup::new(ARGSGRP_POST_TARGET).args(&["group_id", "replied_to_id", "to"]).required(true)))]
+    Post {
+        /// The group to post the message to.
+        #[arg(short, long)]
+        group_id: Option<u64>,
+        /// The message to reply to.
+        #[arg(short, long)]
+        replied_to_id: Option<u64>,
+        /// The comma separated ids of the users to send a private message to.
+        #[arg(long, value_delimiter = ',')]
+        to: Vec<u64>,
+        /// The message body. If neither a body nor a body file is provided, the body is read from stdin.
+        #[arg(short, long)]
+        body: Option<String>,
+        /// A file holding the message body.
+        #[arg(long, conflicts_with = "body")]
+        body_file: Option<PathBuf>,
+    },
+    /// Post one message per row of a CSV or JSON manifest.
+    PostBatch {
+        /// The manifest file. Its group_id, replied_to_id or to column picks where each message
+        /// goes and its template column is the body, with {column} placeholders.
+        manifest: PathBuf,
+        /// A template file used for the rows without a template column.
+        #[arg(long)]
+        template: Option<PathBuf>,
+        /// Print the messages without posting them. Recipient emails are not looked up.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Delete the messages listed in a plan file created by delete --plan-out.
+    ApplyPlan {
+        /// The plan file.
+        plan: PathBuf,
+        /// Append the raw JSON of every message to this file before deleting it.
+        #[arg(long)]
+        archive: Option<PathBuf>,
     },
-}
-
-#[derive(Debug, Clone)]
-pub struct TokenBucket {
-    capacity: usize,
-    tokens: usize,
-    rate_in_seconds: u64,
-    updated: Instant,
-}
-
-impl TokenBucket {
-    pub fn new(capacity: usize, rate: u64) -> Self {
-        Self {
-            capacity,
-            tokens: capacity,
-            rate_in_seconds: rate,
-            updated: Instant::now(),
-        }
-    }
-
-    pub fn take(&mut self) -> bool {
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.updated).as_secs();
-        self.updated = now;
-        self.tokens = (self.tokens as u64 + elapsed * self.rate_in_seconds)
-            .min(self.capacity as u64) as usize;
-
-        if self.tokens > 0 {
-            self.tokens -= 1;
-            true
-        } else {
-            false
-        }
-    }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -152,14 +321,19 @@ pub struct YammerUser {
 }
 
 impl YammerUser {
-    pub fn from_json(user: &Value) -> Self {
+    pub fn from_api(user: &ApiUser) -> Self {
         YammerUser {
-            id: user["id"].as_u64().unwrap(),
-            name: user["full_name"].as_str().unwrap().to_owned(),
-            email: user["email"].as_str().unwrap().to_owned(),
-            network_id: user["network_id"].as_u64().unwrap(),
-            state: user["state"].as_str().unwrap_or("").to_owned(),
-            job_title: user["job_title"].as_str().unwrap_or("").to_owned(),
+            id: user.id,
+            name: user
+                .full_name
+                .as_ref()
+                .or(user.name.as_ref())
+                .cloned()
+                .unwrap_or_default(),
+            email: user.email.clone().unwrap_or_default(),
+            network_id: user.network_id.unwrap_or(0),
+            state: user.state.clone().unwrap_or_default(),
+            job_title: user.job_title.clone().unwrap_or_default(),
         }
     }
 }
@@ -172,11 +346,27 @@ pub struct YammerGroup {
 }
 
 impl YammerGroup {
-    pub fn from_json(group: &Value) -> Self {
+    pub fn from_api(group: &ApiGroup) -> Self {
+        let name = group.name.clone().unwrap_or_default();
         YammerGroup {
-            id: group["id"].as_u64().unwrap(),
-            name: group["name"].as_str().unwrap().to_owned(),
-            display_name: group["full_name"].as_str().unwrap().to_owned(),
+            id: group.id,
+            display_name: group.full_name.clone().unwrap_or(name.clone()),
+            name,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct YammerTopic {
+    pub id: u64,
+    pub name: String,
+}
+
+impl YammerTopic {
+    pub fn from_api(topic: &ApiTopic) -> Self {
+        YammerTopic {
+            id: topic.id,
+            name: topic.name.clone().unwrap_or_default(),
         }
     }
 }
@@ -204,12 +394,12 @@ impl YammerMessage {
         self.replied_to_id.is_none()
     }
 
-    pub fn from_json(
-        message: &Value,
+    pub fn from_api(
+        message: &ApiMessage,
         users: Option<&HashMap<u64, YammerUser>>,
         groups: Option<&HashMap<u64, YammerGroup>>,
     ) -> Self {
-        let sender_id = message["sender_id"].as_u64().unwrap_or(0);
+        let sender_id = message.sender_id.unwrap_or(0);
         let sender_name = if let Some(users) = users {
             users
                 .get(&sender_id)
@@ -218,7 +408,7 @@ impl YammerMessage {
         } else {
             sender_id.to_string()
         };
-        let group_id = message["group_id"].as_u64().unwrap_or(0);
+        let group_id = message.group_id.unwrap_or(0);
         let group_name = if let Some(groups) = groups {
             groups
                 .get(&group_id)
@@ -228,23 +418,125 @@ impl YammerMessage {
             group_id.to_string()
         };
         YammerMessage {
-            id: message["id"].as_u64().unwrap_or(0),
-            replied_to_id: message["replied_to_id"].as_u64(),
-            sender_id: message["sender_id"].as_u64().unwrap_or(0),
-            sender_name: sender_name,
-            network_id: message["network_id"].as_u64().unwrap_or(0),
-            group_id: group_id,
-            group_name: group_name,
-            thread_id: message["thread_id"].as_u64().unwrap_or(0),
-            privacy: message["privacy"].as_str().unwrap().to_owned(),
-            created_at: message["created_at"].as_str().unwrap().to_owned(),
-            body: message["body"]["rich"].as_str().unwrap().to_owned(),
-            liked_by: message["liked_by"]["count"].as_u64().unwrap_or(0),
+            id: message.id,
+            replied_to_id: message.replied_to_id,
+            sender_id,
+            sender_name,
+            network_id: message.network_id.unwrap_or(0),
+            group_id,
+            group_name,
+            thread_id: message.thread_id(),
+            privacy: message.privacy.clone().unwrap_or_default(),
+            created_at: message.created_at.clone().unwrap_or_default(),
+            body: message
+                .body
+                .as_ref()
+                .map(|e| e.text().to_owned())
+                .unwrap_or_default(),
+            liked_by: message.likes(),
             replies: None,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanAction {
+    Delete,
+    Keep,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub action: PlanAction,
+    pub reason: String,
+    #[serde(flatten)]
+    pub message: YammerMessage,
+}
+
+impl PlanEntry {
+    pub fn new(action: PlanAction, reason: &str, message: YammerMessage) -> Self {
+        PlanEntry {
+            action,
+            reason: reason.to_owned(),
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PostStatus {
+    Posted,
+    Planned,
+    Failed,
+}
+
+/// The outcome of a row of a post-batch manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostReport {
+    /// The row number, starting at 1 after the header.
+    pub row: usize,
+    pub status: PostStatus,
+    pub group_id: Option<u64>,
+    pub replied_to_id: Option<u64>,
+    pub to: Vec<u64>,
+    /// The recipient emails a dry run leaves unresolved.
+    pub unresolved: Vec<String>,
+    pub message_id: Option<u64>,
+    pub body: String,
+    pub error: Option<String>,
+}
+
+impl PostReport {
+    pub fn new(row: usize) -> Self {
+        PostReport {
+            row,
+            status: PostStatus::Failed,
+            group_id: None,
+            replied_to_id: None,
+            to: Vec::new(),
+            unresolved: Vec::new(),
+            message_id: None,
+            body: String::new(),
+            error: None,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct DeletePlan {
+    pub created_at: String,
+    pub user_id: Option<u64>,
+    pub messages: Vec<YammerMessage>,
+}
+
+impl DeletePlan {
+    pub fn new(user_id: Option<u64>, entries: &[PlanEntry]) -> Self {
+        DeletePlan {
+            created_at: Local::now().to_rfc3339(),
+            user_id,
+            messages: entries
+                .iter()
+                .filter(|e| e.action == PlanAction::Delete)
+                .map(|e| e.message.clone())
+                .collect(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let plan = serde_json::from_str(&text)?;
+        Ok(plan)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = to_string_pretty(self)?;
+        fs::write(path, json)?;
+        Ok(())
+    }
+}
+
 pub fn build_compatible_client(cookies: &Arc<CookieStoreRwLock>) -> Result<Client> {
     cookies.write().unwrap().clear();
 
@@ -285,6 +577,21 @@ fn random_ua() -> String {
     }
 }
 
+/// The message body from the argument, the file, or stdin, in that order.
+pub fn read_body(body: Option<&str>, file: Option<&Path>) -> Result<String> {
+    let body = match (body, file) {
+        (Some(body), _) => body.to_owned(),
+        (None, Some(file)) => fs::read_to_string(file)?,
+        (None, None) => io::read_to_string(io::stdin())?,
+    };
+
+    if body.trim().is_empty() {
+        return Err(YammerError::EmptyMessage.into());
+    }
+
+    Ok(body)
+}
+
 pub fn parse_excludes(exclude: &str) -> HashSet<u64> {
     if exclude.is_empty() {
         return HashSet::new();
@@ -296,42 +603,3 @@ pub fn parse_excludes(exclude: &str) -> HashSet<u64> {
         .map(|s| s.parse().unwrap())
         .collect()
 }
-
-#[allow(dead_code)]
-pub mod output {
-    use super::*;
-
-    pub fn print_header(appinfo: &AppInfo) {
-        println!(
-            r#"
-★····························································★
-      _____   ° _________   _.★    ·               _____.·★
-    ·/  _  \ ★ /   _____/_/  |_  _______ ¤__ __★  /     \  ·
-  ★ /  /_\  \  \_____  \ \   ___\\_  __ \|  |  \ /  \ /  \·
- · /    |    \ /        \ |  | ·  |  | \/|  |  //    Y    \ ★
-   \____|____//_________/ |__|  · |__|★  |____/ \____|____/·
-  ★·.°      ¤        °·★                ¤··•      ★·
-★····························································★
-·★·.·´¯`·.·★ {} v{} ★·.·´¯`·.·★
-·.•°¤*(¯`★´¯)*¤° {} °¤*)¯´★`¯(*¤°•.
-·★ {} ★·
-"#,
-            appinfo.name, appinfo.version, appinfo.authors, appinfo.description
-        );
-    }
-
-    pub fn print_json(value: &Value) {
-        let json = to_string_pretty(&value).unwrap();
-        println!("{}", json);
-    }
-
-    pub fn print_user(user: &YammerUser) {
-        let json = to_string_pretty(&user).unwrap();
-        println!("{}", json);
-    }
-
-    pub fn print_message(message: &YammerMessage) {
-        let json = to_string_pretty(&message).unwrap();
-        println!("{}", json);
-    }
-}
@@ -0,0 +1,307 @@
+use log::{info, warn};
+use rustmix::{web::reqwest::Url, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    common::Args,
+    error::YammerError,
+    oauth::{OAuthSettings, StoredToken, TokenRefresher},
+    output::OutputFormat,
+    rate_limit::{RateLimit, RateLimits},
+    service::DEFAULT_BASE_URL,
+};
+
+pub const TOKEN_ENV: &str = "YAMMER_TOKEN";
+pub const REFRESH_TOKEN_ENV: &str = "YAMMER_REFRESH_TOKEN";
+pub const BASE_URL_ENV: &str = "YAMMER_BASE_URL";
+
+/// The settings of a network or account. The top level of the config file is
+/// the default profile and every `[profiles.<name>]` table overrides it.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Profile {
+    /// The Yammer token.
+    pub token: Option<String>,
+    /// A file holding the Yammer token.
+    pub token_file: Option<PathBuf>,
+    /// The API base URL.
+    pub base_url: Option<String>,
+    pub read_rate: Option<RateLimit>,
+    pub write_rate: Option<RateLimit>,
+    pub messages_rate: Option<RateLimit>,
+    pub autocomplete_rate: Option<RateLimit>,
+    /// The output format used when neither --format nor the output file picks one.
+    pub format: Option<OutputFormat>,
+    /// The OAuth client and endpoints used by `login`.
+    #[serde(default)]
+    pub oauth: OAuthSettings,
+}
+
+impl Profile {
+    /// `other` layered over this profile, as a named profile is over the
+    /// defaults. Every key `other` sets wins, and the `oauth` tables are merged
+    /// key by key.
+    pub fn merge(&self, other: &Profile) -> Self {
+        Self {
+            token: other.token.clone().or(self.token.clone()),
+            token_file: other.token_file.clone().or(self.token_file.clone()),
+            base_url: other.base_url.clone().or(self.base_url.clone()),
+            read_rate: other.read_rate.or(self.read_rate),
+            write_rate: other.write_rate.or(self.write_rate),
+            messages_rate: other.messages_rate.or(self.messages_rate),
+            autocomplete_rate: other.autocomplete_rate.or(self.autocomplete_rate),
+            format: other.format.or(self.format),
+            oauth: self.oauth.merge(&other.oauth),
+        }
+    }
+
+    /// The rate limits of the command line, then of the profile, then the defaults.
+    pub fn rate_limits(&self, args: &Args) -> RateLimits {
+        let defaults = RateLimits::default();
+        RateLimits {
+            read: args.read_rate.or(self.read_rate).unwrap_or(defaults.read),
+            write: args
+                .write_rate
+                .or(self.write_rate)
+                .unwrap_or(defaults.write),
+            messages: args
+                .messages_rate
+                .or(self.messages_rate)
+                .unwrap_or(defaults.messages),
+            autocomplete: args
+                .autocomplete_rate
+                .or(self.autocomplete_rate)
+                .unwrap_or(defaults.autocomplete),
+            adaptive: !args.fixed_rate,
+        }
+    }
+}
+
+/// The settings read from `~/.config/yamutil/config.toml`:
+///
+/// ```toml
+/// default_profile = "contoso"
+/// format = "table"
+///
+/// [profiles.contoso]
+/// token_file = "/home/me/.contoso-token"
+/// messages_rate = "10/30s"
+///
+/// [profiles.fabrikam]
+/// base_url = "https://yammer.fabrikam.example/api/v1/"
+/// oauth = { client_id = "..." }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// The profile used when --profile is not given.
+    pub default_profile: Option<String>,
+    #[serde(flatten)]
+    pub defaults: Profile,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/yamutil`, or `~/.config/yamutil`.
+    pub fn dir() -> Option<PathBuf> {
+        let dir = env::var_os("XDG_CONFIG_HOME")
+            .filter(|e| !e.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| dirs::home_dir().map(|e| e.join(".config")))?;
+        Some(dir.join(env!("CARGO_PKG_NAME")))
+    }
+
+    pub fn default_path() -> Option<PathBuf> {
+        Some(Self::dir()?.join("config.toml"))
+    }
+
+    /// Loads the config file given on the command line, which must exist, or
+    /// the default one if there is one.
+    pub fn load(path: Option<&Path>) -> Result<(Self, Option<PathBuf>)> {
+        let path = match path {
+            Some(it) => it.to_path_buf(),
+            None => match Self::default_path() {
+                Some(it) if it.is_file() => it,
+                _ => return Ok((Self::default(), None)),
+            },
+        };
+        let text = fs::read_to_string(&path)?;
+        let config: Config = toml::from_str(&text)?;
+        Ok((config, Some(path)))
+    }
+
+    /// The name of the selected profile and its settings merged over the
+    /// defaults. Without a name, the top level settings are used.
+    pub fn profile(&self, name: Option<&str>) -> Result<(Option<String>, Profile)> {
+        let name = match name.or(self.default_profile.as_deref()) {
+            Some(it) => it,
+            None => return Ok((None, self.defaults.clone())),
+        };
+
+        match self.profiles.get(name) {
+            Some(profile) => {
+                info!("Using profile '{}'", name);
+                Ok((Some(name.to_owned()), self.defaults.merge(profile)))
+            }
+            None => Err(YammerError::UnknownProfile {
+                name: name.to_owned(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Resolves the API base URL in this order: `--base-url`, the
+/// `YAMMER_BASE_URL` variable, the profile's `base_url` and the public API.
+/// The URL always ends with a slash so the endpoint paths are appended to it.
+pub fn resolve_base_url(args: &Args, profile: &Profile) -> Result<String> {
+    let url = non_empty(args.base_url.as_deref())
+        .or_else(|| non_empty(env::var(BASE_URL_ENV).ok().as_deref()))
+        .or_else(|| non_empty(profile.base_url.as_deref()))
+        .unwrap_or_else(|| DEFAULT_BASE_URL.to_owned());
+    let mut parsed = match Url::parse(&url) {
+        Ok(it) if matches!(it.scheme(), "http" | "https") => it,
+        _ => return Err(YammerError::InvalidBaseUrl { url }.into()),
+    };
+
+    if !parsed.path().ends_with('/') {
+        let path = format!("{}/", parsed.path());
+        parsed.set_path(&path);
+    }
+
+    if parsed.as_str() != DEFAULT_BASE_URL {
+        info!("Using the API at '{}'", parsed);
+    }
+
+    Ok(parsed.into())
+}
+
+/// Where the token was found. Only the source is ever logged.
+#[derive(Debug)]
+pub enum TokenSource {
+    Argument,
+    File(PathBuf),
+    Environment,
+    Config(PathBuf),
+    Login(PathBuf),
+    Prompt,
+}
+
+impl fmt::Display for TokenSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenSource::Argument => write!(f, "--token"),
+            TokenSource::File(path) => write!(f, "file '{}'", path.display()),
+            TokenSource::Environment => write!(f, "{}", TOKEN_ENV),
+            TokenSource::Config(path) => write!(f, "config '{}'", path.display()),
+            TokenSource::Login(path) => write!(f, "login '{}'", path.display()),
+            TokenSource::Prompt => write!(f, "prompt"),
+        }
+    }
+}
+
+/// Resolves the token in this order: `--token`, `--token-file`, the
+/// `YAMMER_TOKEN` variable (including `.env`), the config file's `token` then
+/// `token_file`, the tokens saved by `login`, and finally an interactive
+/// prompt when stdin is a terminal.
+///
+/// The token comes with a refresher when it can be renewed: the tokens saved
+/// by `login` carry their refresh token, otherwise one can be given with
+/// `--refresh-token` or `YAMMER_REFRESH_TOKEN` along with `oauth.client_id`.
+pub fn resolve_token(
+    args: &Args,
+    profile: &Profile,
+    profile_name: Option<&str>,
+    config_path: Option<&Path>,
+) -> Result<(String, Option<TokenRefresher>)> {
+    let (token, source) = find_token(args, profile, profile_name, config_path)?;
+    info!("Using the token from {}", source);
+
+    if let TokenSource::Login(path) = &source {
+        let stored = StoredToken::load(path)?;
+
+        if stored.refresh_token.is_some() {
+            let refresher = TokenRefresher::from_stored(stored, Some(path.clone()))?;
+            return Ok((token, Some(refresher)));
+        }
+    }
+
+    let refresh_token = non_empty(args.refresh_token.as_deref())
+        .or_else(|| non_empty(env::var(REFRESH_TOKEN_ENV).ok().as_deref()));
+
+    if let Some(refresh_token) = refresh_token {
+        let refresher = TokenRefresher::from_refresh_token(&profile.oauth, &token, &refresh_token)?;
+        return Ok((token, Some(refresher)));
+    }
+
+    Ok((token, None))
+}
+
+fn find_token(
+    args: &Args,
+    profile: &Profile,
+    profile_name: Option<&str>,
+    config_path: Option<&Path>,
+) -> Result<(String, TokenSource)> {
+    if let Some(token) = non_empty(args.token.as_deref()) {
+        return Ok((token, TokenSource::Argument));
+    }
+
+    if let Some(path) = &args.token_file {
+        return Ok((read_token_file(path)?, TokenSource::File(path.clone())));
+    }
+
+    if let Some(token) = non_empty(env::var(TOKEN_ENV).ok().as_deref()) {
+        return Ok((token, TokenSource::Environment));
+    }
+
+    if let Some(token) = non_empty(profile.token.as_deref()) {
+        let path = config_path.map(|e| e.to_path_buf()).unwrap_or_default();
+        return Ok((token, TokenSource::Config(path)));
+    }
+
+    if let Some(path) = &profile.token_file {
+        return Ok((read_token_file(path)?, TokenSource::File(path.clone())));
+    }
+
+    if let Some(path) = StoredToken::default_path(profile_name).filter(|e| e.is_file()) {
+        let stored = StoredToken::load(&path)?;
+
+        if !stored.is_expired() || stored.refresh_token.is_some() {
+            return Ok((stored.access_token, TokenSource::Login(path)));
+        }
+
+        warn!("The token saved by login has expired. Run login again");
+    }
+
+    if io::stdin().is_terminal() {
+        let token = rpassword::prompt_password("Yammer token: ")?;
+
+        if let Some(token) = non_empty(Some(&token)) {
+            return Ok((token, TokenSource::Prompt));
+        }
+    }
+
+    Err(YammerError::MissingToken.into())
+}
+
+fn read_token_file(path: &Path) -> Result<String> {
+    let text = fs::read_to_string(path)?;
+
+    match non_empty(Some(&text)) {
+        Some(it) => Ok(it),
+        None => Err(YammerError::MissingToken.into()),
+    }
+}
+
+fn non_empty(value: Option<&str>) -> Option<String> {
+    value
+        .map(|e| e.trim())
+        .filter(|e| !e.is_empty())
+        .map(|e| e.to_owned())
+}
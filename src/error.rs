@@ -1,2 +1,100 @@
-// use std::fmt::Debug;
-// use thiserror::Error;
+use rustmix::{web::reqwest, Result};
+use std::error::Error;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum YammerError {
+    #[error("Unauthorized. The token is invalid or has expired")]
+    Unauthorized,
+    #[error("Forbidden. The token is not allowed to access this resource")]
+    Forbidden,
+    #[error("The token does not belong to '{email}'. Only their own token can change their likes")]
+    NotCurrentUser { email: String },
+    #[error("Not found")]
+    NotFound,
+    #[error("Rate limit exceeded{}", retry_after.map(|e| format!(". Retry after {} seconds", e)).unwrap_or_default())]
+    RateLimited { retry_after: Option<u64> },
+    #[error("Unexpected response: {body}")]
+    Parse { body: String },
+    #[error("HTTP status {status}: {body}")]
+    Status { status: u16, body: String },
+    #[error("Network error: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("OAuth error {error}: {description}")]
+    OAuth { error: String, description: String },
+    #[error("Profile '{name}' is not in the config file")]
+    UnknownProfile { name: String },
+    #[error("Invalid base URL '{url}'. Expected an http or https URL")]
+    InvalidBaseUrl { url: String },
+    #[error("No recorded response for '{request}'")]
+    MissingFixture { request: String },
+    #[error("The argument '{name}' is empty or invalid")]
+    InvalidArgument { name: String },
+    #[error("The journal '{path}' was written with other filters. Resume with the same --group-id, --thread-id, --email and --exclude")]
+    JournalMismatch { path: String },
+    #[error("The message body is empty")]
+    EmptyMessage,
+    #[error("Invalid manifest row: {reason}")]
+    InvalidRow { reason: String },
+    #[error("No token. Use --token, --token-file, YAMMER_TOKEN, the config file or login, or run interactively")]
+    MissingToken,
+    #[error("Partially completed. {done} succeeded, {failed} failed")]
+    Partial { done: u64, failed: u64 },
+}
+
+impl YammerError {
+    pub fn from_status(status: u16, body: String, retry_after: Option<u64>) -> Self {
+        match status {
+            401 => YammerError::Unauthorized,
+            403 => YammerError::Forbidden,
+            404 => YammerError::NotFound,
+            429 => YammerError::RateLimited { retry_after },
+            _ => YammerError::Status { status, body },
+        }
+    }
+
+    /// `done` when nothing failed, otherwise a `Partial` error counting both.
+    pub fn partial(done: u64, failed: u64) -> Result<u64> {
+        if failed > 0 {
+            return Err(YammerError::Partial { done, failed }.into());
+        }
+
+        Ok(done)
+    }
+
+    /// The process exit code for this error. 1 is left for errors that are
+    /// not a `YammerError`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            YammerError::Unauthorized | YammerError::MissingToken | YammerError::OAuth { .. } => 2,
+            YammerError::Forbidden | YammerError::NotCurrentUser { .. } => 3,
+            YammerError::NotFound => 4,
+            YammerError::RateLimited { .. } => 5,
+            YammerError::Parse { .. } => 6,
+            YammerError::Status { .. } => 7,
+            YammerError::Network(_) => 8,
+            YammerError::Partial { .. } => 9,
+            YammerError::UnknownProfile { .. }
+            | YammerError::InvalidBaseUrl { .. }
+            | YammerError::InvalidArgument { .. }
+            | YammerError::JournalMismatch { .. }
+            | YammerError::EmptyMessage
+            | YammerError::InvalidRow { .. } => 10,
+            YammerError::MissingFixture { .. } => 11,
+        }
+    }
+}
+
+pub fn is_not_found(error: &(dyn Error + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<YammerError>(),
+        Some(YammerError::NotFound)
+    )
+}
+
+pub fn exit_code(error: &(dyn Error + 'static)) -> i32 {
+    error
+        .downcast_ref::<YammerError>()
+        .map(|e| e.exit_code())
+        .unwrap_or(1)
+}
@@ -0,0 +1,123 @@
+use log::info;
+use rustmix::Result;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    api::{ApiAttachment, ApiMessage},
+    common::*,
+};
+
+#[derive(Debug, Serialize)]
+struct ExportRecord<'a> {
+    raw: &'a ApiMessage,
+    message: YammerMessage,
+}
+
+/// Writes an offline archive with the layout:
+///
+/// ```text
+/// <dir>/threads/<thread_id>.jsonl
+/// <dir>/attachments/<message_id>/<attachment_id>-<name>
+/// <dir>/users.jsonl
+/// <dir>/groups.jsonl
+/// ```
+#[derive(Debug)]
+pub struct Exporter {
+    dir: PathBuf,
+    threads: HashSet<u64>,
+}
+
+impl Exporter {
+    pub fn create(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir.join("threads"))?;
+        info!("Exporting to '{}'", dir.display());
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            threads: HashSet::new(),
+        })
+    }
+
+    pub fn has_thread(&self, thread_id: u64) -> bool {
+        self.threads.contains(&thread_id)
+    }
+
+    pub fn write_thread(
+        &mut self,
+        thread_id: u64,
+        messages: &[ApiMessage],
+        users: &HashMap<u64, YammerUser>,
+        groups: &HashMap<u64, YammerGroup>,
+    ) -> Result<()> {
+        let path = self
+            .dir
+            .join("threads")
+            .join(format!("{}.jsonl", thread_id));
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        for raw in messages {
+            let record = ExportRecord {
+                raw,
+                message: YammerMessage::from_api(raw, Some(users), Some(groups)),
+            };
+            writeln!(writer, "{}", serde_json::to_string(&record)?)?;
+        }
+
+        writer.flush()?;
+        self.threads.insert(thread_id);
+        Ok(())
+    }
+
+    pub fn write_attachment(
+        &self,
+        message_id: u64,
+        attachment: &ApiAttachment,
+        bytes: &[u8],
+    ) -> Result<PathBuf> {
+        let dir = self.dir.join("attachments").join(message_id.to_string());
+        fs::create_dir_all(&dir)?;
+        let id = attachment.id.unwrap_or(0);
+        let name = attachment.name.as_deref().unwrap_or("attachment");
+        let path = dir.join(format!("{}-{}", id, sanitize_file_name(name)));
+        fs::write(&path, bytes)?;
+        Ok(path)
+    }
+
+    pub fn write_users(&self, users: &HashMap<u64, YammerUser>) -> Result<()> {
+        write_lines(&self.dir.join("users.jsonl"), users.values())
+    }
+
+    pub fn write_groups(&self, groups: &HashMap<u64, YammerGroup>) -> Result<()> {
+        write_lines(&self.dir.join("groups.jsonl"), groups.values())
+    }
+}
+
+fn write_lines<'a, T, I>(path: &Path, items: I) -> Result<()>
+where
+    T: Serialize + 'a,
+    I: Iterator<Item = &'a T>,
+{
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    for item in items {
+        writeln!(writer, "{}", serde_json::to_string(item)?)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
@@ -0,0 +1,204 @@
+//! Recording and replaying of the HTTP exchanges of a run. Every exchange is
+//! saved as `<method>-<hash>-<n>.json` in the fixtures directory, where the
+//! hash identifies the path and query and `n` counts the identical requests,
+//! so a replay answers repeated requests (pages, retries) in the same order.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, info};
+use rustmix::{
+    web::reqwest::{header::HeaderMap, Request, Response},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::error::YammerError;
+
+const REDACTED: &str = "<redacted>";
+/// Headers that carry credentials. Their values are never written.
+const SECRET_HEADERS: [&str; 3] = ["authorization", "cookie", "set-cookie"];
+/// Headers that describe the body as it was on the wire, not as it is saved.
+const WIRE_HEADERS: [&str; 3] = ["content-encoding", "content-length", "transfer-encoding"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixtureMode {
+    Record,
+    Replay,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Exchange {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    url: String,
+    headers: BTreeMap<String, String>,
+    body: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: BTreeMap<String, String>,
+    body: String,
+    /// Set when the body is not UTF-8 and was saved as base64.
+    #[serde(default)]
+    base64: bool,
+}
+
+/// A directory of recorded exchanges shared by every clone of a `Service`.
+#[derive(Debug)]
+pub struct Fixtures {
+    mode: FixtureMode,
+    dir: PathBuf,
+    seen: Mutex<HashMap<String, u32>>,
+}
+
+impl Fixtures {
+    pub fn record(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        info!("Recording the HTTP exchanges to '{}'", dir.display());
+        Ok(Self::new(FixtureMode::Record, dir))
+    }
+
+    pub fn replay(dir: &Path) -> Result<Self> {
+        fs::read_dir(dir)?;
+        info!("Replaying the HTTP exchanges from '{}'", dir.display());
+        Ok(Self::new(FixtureMode::Replay, dir))
+    }
+
+    fn new(mode: FixtureMode, dir: &Path) -> Self {
+        Self {
+            mode,
+            dir: dir.to_path_buf(),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn mode(&self) -> FixtureMode {
+        self.mode
+    }
+
+    /// Saves the exchange and returns an equivalent response, because reading
+    /// the body consumes the original one.
+    pub async fn save(&self, request: &Request, response: Response) -> Result<Response> {
+        let path = self.path(request, self.next(request));
+        let status = response.status().as_u16();
+        let headers = recorded_headers(response.headers());
+        let bytes = response.bytes().await.map_err(YammerError::Network)?;
+        let (body, base64) = match String::from_utf8(bytes.to_vec()) {
+            Ok(it) => (it, false),
+            Err(_) => (STANDARD.encode(&bytes), true),
+        };
+        let exchange = Exchange {
+            request: RecordedRequest {
+                method: request.method().to_string(),
+                url: request.url().to_string(),
+                headers: recorded_headers(request.headers()),
+                body: request
+                    .body()
+                    .and_then(|e| e.as_bytes())
+                    .map(|e| String::from_utf8_lossy(e).into_owned()),
+            },
+            response: RecordedResponse {
+                status,
+                headers,
+                body,
+                base64,
+            },
+        };
+        fs::write(&path, serde_json::to_string_pretty(&exchange)?)?;
+        debug!("Recorded '{}' to '{}'", request.url(), path.display());
+        build_response(&exchange.response, bytes.to_vec())
+    }
+
+    /// The recorded response of the request. A request made more often than
+    /// it was recorded gets the last recorded response.
+    pub fn load(&self, request: &Request) -> Result<Response> {
+        let key = key(request);
+        let mut n = self.next(request);
+        let mut path = self.path(request, n);
+
+        while !path.is_file() {
+            if n == 0 {
+                return Err(YammerError::MissingFixture { request: key }.into());
+            }
+
+            n -= 1;
+            path = self.path(request, n);
+        }
+
+        let text = fs::read_to_string(&path)?;
+        let exchange: Exchange = serde_json::from_str(&text)?;
+        let body = match exchange.response.base64 {
+            true => STANDARD.decode(&exchange.response.body)?,
+            false => exchange.response.body.clone().into_bytes(),
+        };
+        debug!("Replaying '{}' from '{}'", key, path.display());
+        build_response(&exchange.response, body)
+    }
+
+    /// Counts the request and returns how many identical ones came before it.
+    fn next(&self, request: &Request) -> u32 {
+        let mut seen = self.seen.lock().unwrap();
+        let n = seen.entry(key(request)).or_insert(0);
+        *n += 1;
+        *n - 1
+    }
+
+    fn path(&self, request: &Request, n: u32) -> PathBuf {
+        let hash = Sha256::digest(key(request).as_bytes());
+        let hash: String = hash.iter().take(8).map(|e| format!("{:02x}", e)).collect();
+        self.dir
+            .join(format!("{}-{}-{}.json", request.method(), hash, n))
+    }
+}
+
+/// The method, path and query of the request. The host is left out so the
+/// fixtures replay against any base URL.
+fn key(request: &Request) -> String {
+    let url = request.url();
+
+    match url.query() {
+        Some(query) => format!("{} {}?{}", request.method(), url.path(), query),
+        None => format!("{} {}", request.method(), url.path()),
+    }
+}
+
+fn recorded_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let name = name.as_str().to_lowercase();
+            let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_owned()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name, value)
+        })
+        .collect()
+}
+
+fn build_response(recorded: &RecordedResponse, body: Vec<u8>) -> Result<Response> {
+    let mut builder = http::Response::builder().status(recorded.status);
+
+    for (name, value) in &recorded.headers {
+        if SECRET_HEADERS.contains(&name.as_str()) || WIRE_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+
+        builder = builder.header(name, value);
+    }
+
+    Ok(Response::from(builder.body(body)?))
+}
@@ -0,0 +1,201 @@
+use chrono::Local;
+use log::info;
+use rustmix::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{common::*, error::YammerError};
+
+/// The filters of a delete run. They are the first line of its journal so a
+/// resumed run can check that it deletes the same messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeleteFilters {
+    pub group_id: Option<u64>,
+    pub thread_id: Option<u64>,
+    pub user_id: Option<u64>,
+    /// The excluded message ids, sorted.
+    pub exclude: Vec<u64>,
+}
+
+impl DeleteFilters {
+    pub fn new(
+        group_id: Option<u64>,
+        thread_id: Option<u64>,
+        user_id: Option<u64>,
+        exclude: &HashSet<u64>,
+    ) -> Self {
+        let mut exclude: Vec<u64> = exclude.iter().copied().collect();
+        exclude.sort();
+        Self {
+            group_id,
+            thread_id,
+            user_id,
+            exclude,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum JournalRecord {
+    Filters(DeleteFilters),
+    Deleted {
+        message_id: u64,
+        thread_id: u64,
+    },
+    Skipped {
+        message_id: u64,
+        thread_id: u64,
+        reason: String,
+    },
+    Thread {
+        thread_id: u64,
+    },
+    Cursor {
+        older_than: u64,
+    },
+}
+
+/// An append-only JSON-lines log of a delete run. Every line is flushed as
+/// soon as it is written so an aborted run can be resumed from the last
+/// finished thread. The first line holds the filters of the run.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    threads: HashSet<u64>,
+    older_than: Option<u64>,
+}
+
+impl Journal {
+    pub fn create(filters: &DeleteFilters) -> Result<Self> {
+        let path = LOGDIR.join(
+            Local::now()
+                .format("yam-delete-%Y%m%d%H%M%S.jsonl")
+                .to_string(),
+        );
+        fs::create_dir_all(&*LOGDIR)?;
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)?;
+        info!("Writing deletion journal to '{}'", path.display());
+        let mut journal = Self {
+            path,
+            file,
+            threads: HashSet::new(),
+            older_than: None,
+        };
+        journal.write(&JournalRecord::Filters(filters.clone()))?;
+        Ok(journal)
+    }
+
+    /// Reopens the journal of an interrupted run. The run must use the filters
+    /// the journal was written with. An empty journal takes the filters.
+    pub fn resume(path: &Path, filters: &DeleteFilters) -> Result<Self> {
+        let mut threads = HashSet::new();
+        let mut older_than = None;
+        let mut written_filters = None;
+        let mut is_empty = true;
+        let reader = BufReader::new(File::open(path)?);
+
+        for line in reader.lines() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            // The last line may be truncated if the process was killed while writing it
+            let record = match serde_json::from_str::<JournalRecord>(&line) {
+                Ok(it) => it,
+                Err(_) => continue,
+            };
+            is_empty = false;
+
+            match record {
+                JournalRecord::Filters(it) => written_filters = Some(it),
+                JournalRecord::Thread { thread_id } => {
+                    threads.insert(thread_id);
+                }
+                JournalRecord::Cursor { older_than: id } => older_than = Some(id),
+                _ => {}
+            }
+        }
+
+        if !is_empty && written_filters.as_ref() != Some(filters) {
+            return Err(YammerError::JournalMismatch {
+                path: path.display().to_string(),
+            }
+            .into());
+        }
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        info!(
+            "Resuming deletion journal '{}' with {} finished threads",
+            path.display(),
+            threads.len()
+        );
+        let mut journal = Self {
+            path: path.to_path_buf(),
+            file,
+            threads,
+            older_than,
+        };
+
+        if is_empty {
+            journal.write(&JournalRecord::Filters(filters.clone()))?;
+        }
+
+        Ok(journal)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn older_than(&self) -> Option<u64> {
+        self.older_than
+    }
+
+    pub fn is_finished(&self, thread_id: u64) -> bool {
+        self.threads.contains(&thread_id)
+    }
+
+    pub fn deleted(&mut self, message_id: u64, thread_id: u64) -> Result<()> {
+        self.write(&JournalRecord::Deleted {
+            message_id,
+            thread_id,
+        })
+    }
+
+    pub fn skipped(&mut self, message_id: u64, thread_id: u64, reason: &str) -> Result<()> {
+        self.write(&JournalRecord::Skipped {
+            message_id,
+            thread_id,
+            reason: reason.to_owned(),
+        })
+    }
+
+    pub fn finish_thread(&mut self, thread_id: u64) -> Result<()> {
+        self.threads.insert(thread_id);
+        self.write(&JournalRecord::Thread { thread_id })
+    }
+
+    pub fn cursor(&mut self, older_than: u64) -> Result<()> {
+        self.older_than = Some(older_than);
+        self.write(&JournalRecord::Cursor { older_than })
+    }
+
+    fn write(&mut self, record: &JournalRecord) -> Result<()> {
+        let line = serde_json::to_string(record)?;
+        writeln!(self.file, "{}", line)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
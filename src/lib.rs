@@ -0,0 +1,17 @@
+//! The library behind the `yamutil` binary. It is split out so the
+//! integration tests can drive `ActionHandler` against a mock API.
+pub mod action_handler;
+pub mod api;
+pub mod archive;
+pub mod common;
+pub mod config;
+pub mod error;
+pub mod export;
+pub mod fixture;
+pub mod journal;
+pub mod manifest;
+pub mod oauth;
+pub mod output;
+pub mod rate_limit;
+pub mod retry;
+pub mod service;
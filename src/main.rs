@@ -1,9 +1,3 @@
-mod action_handler;
-mod common;
-mod error;
-mod service;
-
-use action_handler::ActionHandler;
 use chrono::Local;
 use clap::Parser;
 use dotenv::dotenv;
@@ -18,8 +12,16 @@ use rustmix::{
     *,
 };
 use std::{sync::Arc, time::Instant};
-
-use crate::{common::*, service::*};
+use yamutil::{
+    action_handler::ActionHandler,
+    common::*,
+    config::{self, resolve_base_url, resolve_token},
+    error,
+    fixture::Fixtures,
+    output::{OutputFormat, OutputWriter},
+    retry::RetryPolicy,
+    service::*,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -32,18 +34,94 @@ async fn main() -> Result<()> {
     let gaurd = log4rs::from_config(configure_log()?)?;
     info!("{} v{} started", APP_INFO.name, APP_INFO.version);
 
-    let service = Arc::new(Service::new());
-    let handler = ActionHandler::new(service);
+    let (config, config_path) = config::Config::load(args.config.as_deref())?;
+    let profile = config
+        .profile(args.profile.as_deref())
+        .and_then(|(name, profile)| Ok((name, resolve_base_url(&args, &profile)?, profile)));
+    let (profile_name, base_url, profile) = match profile {
+        Ok(it) => it,
+        Err(e) => {
+            error!("{}", e.get_message());
+            drop(gaurd);
+            std::process::exit(error::exit_code(e.as_ref()));
+        }
+    };
+    let retry = RetryPolicy::new(args.max_retries, args.retry_delay, args.max_retry_delay);
+    let limits = profile.rate_limits(&args);
+    // login is the one action that does not need a token
+    let auth = match &args.action {
+        YammerAction::Login { .. } => Ok((String::new(), None)),
+        // a replay never reaches the API, so it does not need a token either
+        _ if args.replay.is_some() => Ok((String::new(), None)),
+        _ => resolve_token(
+            &args,
+            &profile,
+            profile_name.as_deref(),
+            config_path.as_deref(),
+        ),
+    };
+    let (token, refresher) = match auth {
+        Ok((token, refresher)) => (Ok(token), refresher),
+        Err(e) => (Err(e), None),
+    };
+    let mut service = Service::new(&base_url, retry, limits);
+
+    if let Some(refresher) = refresher {
+        service = service.with_token_refresher(refresher);
+    }
+
+    if let Some(dir) = &args.record {
+        service = service.with_fixtures(Fixtures::record(dir)?);
+    } else if let Some(dir) = &args.replay {
+        service = service.with_fixtures(Fixtures::replay(dir)?);
+    }
+
+    let service = Arc::new(service);
+    let output = match &args.output {
+        Some(path) => {
+            let format = args
+                .format
+                .or_else(|| OutputFormat::from_path(path))
+                .or(profile.format)
+                .unwrap_or_default();
+            info!("Writing results to '{}'", path.display());
+            OutputWriter::create(format, path)?
+        }
+        None => OutputWriter::new(args.format.or(profile.format).unwrap_or_default()),
+    };
+    let output = Arc::new(output);
+    let handler = ActionHandler::new(
+        service.clone(),
+        output.clone(),
+        args.concurrency as usize,
+        profile.oauth.clone(),
+        profile_name,
+    );
     let start = Instant::now();
-    match handler.process(&args.token, &args.action).await {
-        Ok(_) => {}
+    let result = match token {
+        Ok(token) => handler.process(&token, &args.action).await,
+        Err(e) => Err(e),
+    };
+    let code = match result {
+        Ok(_) => 0,
         Err(e) => {
             error!("{}", e.get_message());
+            error::exit_code(e.as_ref())
         }
     };
+    if let Err(e) = output.finish() {
+        error!("{}", e.get_message());
+    }
+    info!("Requests: {}", service.retry_stats());
+    info!("Effective rates: {}", service.rates().await);
     info!("Elapsed: {}", format_duration(start.elapsed()));
     info!("{} v{} finished", APP_INFO.name, APP_INFO.version);
     drop(gaurd);
+
+    if code != 0 {
+        std::process::exit(code);
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,162 @@
+//! The manifest of `post-batch`: a CSV file with a header row, or a JSON array
+//! of objects, with one message per row. The `group_id`, `replied_to_id` or
+//! `to` column picks where the message goes, and the `template` column (or the
+//! `--template` file) is its body with `{column}` placeholders filled from the
+//! row. `{{` and `}}` stand for literal braces.
+use rustmix::Result;
+use serde_json::Value;
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::error::YammerError;
+
+pub type Row = BTreeMap<String, String>;
+
+/// A rendered row. The recipients are user ids or emails.
+#[derive(Debug, Clone, Default)]
+pub struct ManifestRow {
+    pub group_id: Option<u64>,
+    pub replied_to_id: Option<u64>,
+    pub to: Vec<String>,
+    pub body: String,
+}
+
+/// Loads a `.json` manifest as a JSON array and anything else as CSV.
+pub fn load(path: &Path) -> Result<Vec<Row>> {
+    let is_json = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+
+    if is_json {
+        let text = fs::read_to_string(path)?;
+        let items: Vec<BTreeMap<String, Value>> = serde_json::from_str(&text)?;
+        let rows = items
+            .into_iter()
+            .map(|item| {
+                item.into_iter()
+                    .filter_map(|(key, value)| Some((key, json_cell(value)?)))
+                    .collect()
+            })
+            .collect();
+        return Ok(rows);
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::Headers)
+        .from_path(path)?;
+    let mut rows = Vec::new();
+
+    for row in reader.deserialize() {
+        rows.push(row?);
+    }
+
+    Ok(rows)
+}
+
+/// The target and body of a row. `template` is used when the row has no
+/// `template` column.
+pub fn parse_row(row: &Row, template: Option<&str>) -> Result<ManifestRow> {
+    let group_id = id_cell(row, "group_id")?;
+    let replied_to_id = id_cell(row, "replied_to_id")?;
+    let to: Vec<String> = cell(row, "to")
+        .map(|e| {
+            e.split([';', ' '])
+                .map(|e| e.trim())
+                .filter(|e| !e.is_empty())
+                .map(|e| e.to_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    let targets = [group_id.is_some(), replied_to_id.is_some(), !to.is_empty()];
+
+    if targets.iter().filter(|e| **e).count() != 1 {
+        return Err(invalid_row("expected exactly one of group_id, replied_to_id or to").into());
+    }
+
+    let template = match cell(row, "template").or(template) {
+        Some(it) => it,
+        None => return Err(invalid_row("no template column and no --template").into()),
+    };
+    let body = render(template, row)?;
+
+    if body.trim().is_empty() {
+        return Err(YammerError::EmptyMessage.into());
+    }
+
+    Ok(ManifestRow {
+        group_id,
+        replied_to_id,
+        to,
+        body,
+    })
+}
+
+/// Replaces every `{column}` of the template with the row's value.
+pub fn render(template: &str, row: &Row) -> Result<String> {
+    let mut body = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                body.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                body.push('}');
+            }
+            '{' => {
+                let name: String = chars.by_ref().take_while(|e| *e != '}').collect();
+
+                match row.get(name.trim()) {
+                    Some(value) => body.push_str(value),
+                    None => {
+                        return Err(
+                            invalid_row(&format!("unknown placeholder '{{{}}}'", name)).into()
+                        );
+                    }
+                }
+            }
+            _ => body.push(c),
+        }
+    }
+
+    Ok(body)
+}
+
+fn cell<'a>(row: &'a Row, name: &str) -> Option<&'a str> {
+    row.get(name).map(|e| e.trim()).filter(|e| !e.is_empty())
+}
+
+fn id_cell(row: &Row, name: &str) -> Result<Option<u64>> {
+    match cell(row, name) {
+        Some(value) => match value.parse() {
+            Ok(it) => Ok(Some(it)),
+            Err(_) => Err(invalid_row(&format!("invalid {} '{}'", name, value)).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+fn json_cell(value: Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(it) => Some(it),
+        Value::Array(items) => Some(
+            items
+                .into_iter()
+                .filter_map(json_cell)
+                .collect::<Vec<_>>()
+                .join(";"),
+        ),
+        other => Some(other.to_string()),
+    }
+}
+
+fn invalid_row(reason: &str) -> YammerError {
+    YammerError::InvalidRow {
+        reason: reason.to_owned(),
+    }
+}
@@ -0,0 +1,627 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use humantime::format_duration;
+use log::{info, warn};
+use rustmix::{random, web::reqwest::Client, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    time::{sleep, timeout, Instant},
+};
+use url::Url;
+
+use crate::{common::TIMEOUT, config::Config, error::YammerError};
+
+pub const DEFAULT_AUTHORIZE_URL: &str =
+    "https://login.microsoftonline.com/common/oauth2/v2.0/authorize";
+pub const DEFAULT_TOKEN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
+pub const DEFAULT_DEVICE_CODE_URL: &str =
+    "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode";
+pub const DEFAULT_SCOPE: &str = "https://api.yammer.com/user_impersonation offline_access";
+
+const RANDOM_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// A token this close to its expiry is treated as expired.
+const EXPIRY_MARGIN_SECONDS: i64 = 60;
+/// How long the authorization code flow waits for the browser redirect.
+const LOGIN_TIMEOUT_SECONDS: u64 = 300;
+
+/// The `[oauth]` table of the config file. The login options override it.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct OAuthSettings {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub authorize_url: Option<String>,
+    pub token_url: Option<String>,
+    pub device_code_url: Option<String>,
+    pub scope: Option<String>,
+}
+
+impl OAuthSettings {
+    /// The client, scope and authorize, token and device code endpoints of
+    /// `other`, falling back to these settings for the ones it leaves out.
+    pub fn merge(&self, other: &OAuthSettings) -> Self {
+        Self {
+            client_id: other.client_id.clone().or(self.client_id.clone()),
+            client_secret: other.client_secret.clone().or(self.client_secret.clone()),
+            authorize_url: other.authorize_url.clone().or(self.authorize_url.clone()),
+            token_url: other.token_url.clone().or(self.token_url.clone()),
+            device_code_url: other
+                .device_code_url
+                .clone()
+                .or(self.device_code_url.clone()),
+            scope: other.scope.clone().or(self.scope.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub expires_in: Option<u64>,
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+    message: Option<String>,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+/// An OAuth 2.0 client for the authorization code flow with a loopback
+/// redirect and PKCE, the device code flow and refreshing tokens.
+#[derive(Debug, Clone)]
+pub struct OAuthClient {
+    http: Client,
+    client_id: String,
+    client_secret: Option<String>,
+    authorize_url: String,
+    token_url: String,
+    device_code_url: String,
+    scope: String,
+    login_timeout: Duration,
+}
+
+impl OAuthClient {
+    pub fn new(settings: &OAuthSettings) -> Result<Self> {
+        let client_id = match &settings.client_id {
+            Some(it) if !it.is_empty() => it.clone(),
+            _ => {
+                return Err(YammerError::OAuth {
+                    error: "invalid_request".to_owned(),
+                    description:
+                        "No client id. Use --client-id or oauth.client_id in the config file"
+                            .to_owned(),
+                }
+                .into())
+            }
+        };
+        let http = Client::builder()
+            .timeout(Duration::from_secs(TIMEOUT))
+            .build()?;
+        Ok(Self {
+            http,
+            client_id,
+            client_secret: settings.client_secret.clone(),
+            authorize_url: settings
+                .authorize_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_AUTHORIZE_URL.to_owned()),
+            token_url: settings
+                .token_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_TOKEN_URL.to_owned()),
+            device_code_url: settings
+                .device_code_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_DEVICE_CODE_URL.to_owned()),
+            scope: settings
+                .scope
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SCOPE.to_owned()),
+            login_timeout: Duration::from_secs(LOGIN_TIMEOUT_SECONDS),
+        })
+    }
+
+    /// Sets how long `authorization_code` waits for the browser redirect.
+    pub fn with_login_timeout(mut self, timeout: Duration) -> Self {
+        self.login_timeout = timeout;
+        self
+    }
+
+    /// Prints the authorization URL and waits for the browser to be
+    /// redirected to a listener on `127.0.0.1:<port>`. Port 0 picks a free one.
+    /// Gives up if the browser is not redirected within the login timeout.
+    pub async fn authorization_code(&self, port: u16) -> Result<TokenResponse> {
+        self.authorization_code_with(port, |url| {
+            eprintln!("Open this URL in a browser to sign in:\n\n{}\n", url)
+        })
+        .await
+    }
+
+    /// Like `authorization_code`, but hands the authorization URL to `open`
+    /// instead of printing it.
+    pub async fn authorization_code_with<F>(&self, port: u16, open: F) -> Result<TokenResponse>
+    where
+        F: FnOnce(&Url),
+    {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        // The address the listener is bound to, as localhost might resolve to ::1
+        let redirect_uri = format!("http://127.0.0.1:{}/", listener.local_addr()?.port());
+        let state = random_string(32);
+        let verifier = random_string(64);
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        let url = Url::parse_with_params(
+            &self.authorize_url,
+            &[
+                ("client_id", self.client_id.as_str()),
+                ("response_type", "code"),
+                ("redirect_uri", redirect_uri.as_str()),
+                ("scope", self.scope.as_str()),
+                ("state", state.as_str()),
+                ("code_challenge", challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+        open(&url);
+        info!("Waiting for the redirect on '{}'", redirect_uri);
+        let code = match timeout(self.login_timeout, wait_for_code(&listener, &state)).await {
+            Ok(it) => it?,
+            Err(_) => {
+                return Err(YammerError::OAuth {
+                    error: "timeout".to_owned(),
+                    description: format!(
+                        "No sign in within {}. Run login again",
+                        format_duration(self.login_timeout)
+                    ),
+                }
+                .into())
+            }
+        };
+        self.request_token(&[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &redirect_uri),
+            ("code_verifier", &verifier),
+        ])
+        .await
+    }
+
+    /// Prints the code the user enters on another device and polls the token
+    /// endpoint until the user signs in or the code expires.
+    pub async fn device_code(&self) -> Result<TokenResponse> {
+        let response = self
+            .http
+            .post(&self.device_code_url)
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("scope", self.scope.as_str()),
+            ])
+            .send()
+            .await
+            .map_err(YammerError::Network)?;
+        let device: DeviceCodeResponse = parse_response(response).await?;
+
+        match &device.message {
+            Some(message) => eprintln!("{}", message),
+            None => eprintln!(
+                "Open {} and enter the code {}",
+                device.verification_uri, device.user_code
+            ),
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(device.expires_in);
+        let mut interval = device.interval.max(1);
+
+        while Instant::now() < deadline {
+            sleep(Duration::from_secs(interval)).await;
+            let result = self
+                .request_token(&[
+                    ("grant_type", DEVICE_CODE_GRANT),
+                    ("device_code", &device.device_code),
+                ])
+                .await;
+
+            match result {
+                Ok(it) => return Ok(it),
+                Err(e) => match e.downcast_ref::<YammerError>() {
+                    Some(YammerError::OAuth { error, .. }) if error == "authorization_pending" => {}
+                    Some(YammerError::OAuth { error, .. }) if error == "slow_down" => interval += 5,
+                    _ => return Err(e),
+                },
+            }
+        }
+
+        Err(YammerError::OAuth {
+            error: "expired_token".to_owned(),
+            description: "The device code expired before the sign in completed".to_owned(),
+        }
+        .into())
+    }
+
+    pub async fn refresh(&self, refresh_token: &str) -> Result<TokenResponse> {
+        self.request_token(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("scope", &self.scope),
+        ])
+        .await
+    }
+
+    async fn request_token(&self, params: &[(&str, &str)]) -> Result<TokenResponse> {
+        let mut form: Vec<(&str, &str)> = params.to_vec();
+        form.push(("client_id", &self.client_id));
+
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let response = self
+            .http
+            .post(&self.token_url)
+            .form(&form)
+            .send()
+            .await
+            .map_err(YammerError::Network)?;
+        parse_response(response).await
+    }
+}
+
+async fn parse_response<T: for<'de> Deserialize<'de>>(
+    response: rustmix::web::reqwest::Response,
+) -> Result<T> {
+    let status = response.status();
+    let text = response.text().await.map_err(YammerError::Network)?;
+
+    if !status.is_success() {
+        if let Ok(e) = serde_json::from_str::<ErrorResponse>(&text) {
+            return Err(YammerError::OAuth {
+                error: e.error,
+                description: e.error_description.unwrap_or_default(),
+            }
+            .into());
+        }
+
+        return Err(YammerError::from_status(status.as_u16(), text, None).into());
+    }
+
+    match serde_json::from_str::<T>(&text) {
+        Ok(it) => Ok(it),
+        Err(_) => Err(YammerError::Parse { body: text }.into()),
+    }
+}
+
+/// Accepts connections until one carries the authorization response. Other
+/// requests, such as the browser asking for a favicon, get a 404.
+async fn wait_for_code(listener: &TcpListener, state: &str) -> Result<String> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let target = match read_request_target(&mut stream).await? {
+            Some(it) => it,
+            None => continue,
+        };
+        let url = Url::parse("http://localhost")?.join(&target)?;
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.into_owned())
+        };
+
+        if param("code").is_none() && param("error").is_none() {
+            respond(&mut stream, "404 Not Found", "Not found").await?;
+            continue;
+        }
+
+        if param("state").as_deref() != Some(state) {
+            respond(
+                &mut stream,
+                "400 Bad Request",
+                "The sign in state does not match.",
+            )
+            .await?;
+            return Err(YammerError::OAuth {
+                error: "invalid_state".to_owned(),
+                description: "The state of the redirect does not match the request".to_owned(),
+            }
+            .into());
+        }
+
+        if let Some(error) = param("error") {
+            respond(
+                &mut stream,
+                "200 OK",
+                "Sign in failed. You can close this window.",
+            )
+            .await?;
+            return Err(YammerError::OAuth {
+                error,
+                description: param("error_description").unwrap_or_default(),
+            }
+            .into());
+        }
+
+        respond(
+            &mut stream,
+            "200 OK",
+            "Signed in. You can close this window.",
+        )
+        .await?;
+        return Ok(param("code").unwrap_or_default());
+    }
+}
+
+/// Reads the request head and returns the request target of a GET.
+async fn read_request_target(stream: &mut TcpStream) -> Result<Option<String>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    while !buffer.windows(4).any(|e| e == b"\r\n\r\n") && buffer.len() < 16 * 1024 {
+        let n = stream.read(&mut chunk).await?;
+
+        if n == 0 {
+            break;
+        }
+
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buffer);
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => Ok(Some(target.to_owned())),
+        _ => Ok(None),
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, message: &str) -> Result<()> {
+    let body = format!(
+        "<!DOCTYPE html><html><body><p>{}</p></body></html>",
+        message
+    );
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn random_string(len: usize) -> String {
+    (0..len)
+        .map(|_| RANDOM_ALPHABET[random::numeric(0..RANDOM_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// The tokens saved by `login`, along with what is needed to refresh them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// RFC 3339.
+    pub expires_at: Option<String>,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub token_url: String,
+    pub scope: String,
+}
+
+impl StoredToken {
+    pub fn new(client: &OAuthClient, response: TokenResponse) -> Self {
+        Self {
+            access_token: response.access_token,
+            refresh_token: response.refresh_token,
+            expires_at: response
+                .expires_in
+                .map(|e| (Utc::now() + ChronoDuration::seconds(e as i64)).to_rfc3339()),
+            client_id: client.client_id.clone(),
+            client_secret: client.client_secret.clone(),
+            token_url: client.token_url.clone(),
+            scope: client.scope.clone(),
+        }
+    }
+
+    /// `tokens.json`, or `tokens-<profile>.json`, next to the default config file.
+    pub fn default_path(profile: Option<&str>) -> Option<PathBuf> {
+        let name = match profile {
+            Some(it) => format!("tokens-{}.json", it),
+            None => "tokens.json".to_owned(),
+        };
+        Some(Config::dir()?.join(name))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let token = serde_json::from_str(&text)?;
+        Ok(token)
+    }
+
+    /// Saves the tokens readable by the current user only.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let expires_at = match &self.expires_at {
+            Some(it) => it,
+            None => return false,
+        };
+
+        match DateTime::parse_from_rfc3339(expires_at) {
+            Ok(it) => it - ChronoDuration::seconds(EXPIRY_MARGIN_SECONDS) <= Utc::now(),
+            Err(_) => true,
+        }
+    }
+}
+
+/// Renews the access token with the refresh token when it expires or the API
+/// answers 401. Requests that failed with the same token share one refresh.
+#[derive(Debug)]
+pub struct TokenRefresher {
+    client: OAuthClient,
+    token: Mutex<StoredToken>,
+    path: Option<PathBuf>,
+}
+
+impl TokenRefresher {
+    /// A refresher for the tokens saved by `login`. The renewed tokens are
+    /// saved back to `path`.
+    pub fn from_stored(token: StoredToken, path: Option<PathBuf>) -> Result<Self> {
+        let client = OAuthClient::new(&OAuthSettings {
+            client_id: Some(token.client_id.clone()),
+            client_secret: token.client_secret.clone(),
+            token_url: Some(token.token_url.clone()),
+            scope: Some(token.scope.clone()),
+            ..Default::default()
+        })?;
+        Ok(Self::new(client, token, path))
+    }
+
+    /// A refresher for a refresh token given on the command line. Nothing is saved.
+    pub fn from_refresh_token(
+        settings: &OAuthSettings,
+        access_token: &str,
+        refresh_token: &str,
+    ) -> Result<Self> {
+        let client = OAuthClient::new(settings)?;
+        let token = StoredToken {
+            access_token: access_token.to_owned(),
+            refresh_token: Some(refresh_token.to_owned()),
+            expires_at: None,
+            client_id: client.client_id.clone(),
+            client_secret: client.client_secret.clone(),
+            token_url: client.token_url.clone(),
+            scope: client.scope.clone(),
+        };
+        Ok(Self::new(client, token, None))
+    }
+
+    fn new(client: OAuthClient, token: StoredToken, path: Option<PathBuf>) -> Self {
+        Self {
+            client,
+            token: Mutex::new(token),
+            path,
+        }
+    }
+
+    /// The current access token, renewed first if it has expired.
+    pub async fn access_token(&self) -> Result<String> {
+        let mut token = self.token.lock().await;
+
+        if token.is_expired() {
+            self.renew(&mut token).await?;
+        }
+
+        Ok(token.access_token.clone())
+    }
+
+    /// Renews the access token unless it changed since `stale` was handed out.
+    pub async fn refresh(&self, stale: &str) -> Result<String> {
+        let mut token = self.token.lock().await;
+
+        if token.access_token == stale {
+            self.renew(&mut token).await?;
+        }
+
+        Ok(token.access_token.clone())
+    }
+
+    async fn renew(&self, token: &mut StoredToken) -> Result<()> {
+        let refresh_token = match &token.refresh_token {
+            Some(it) => it.clone(),
+            None => return Err(YammerError::Unauthorized.into()),
+        };
+        info!("Refreshing the access token");
+        let response = self.client.refresh(&refresh_token).await?;
+        let mut renewed = StoredToken::new(&self.client, response);
+
+        // The refresh token is not always rotated
+        if renewed.refresh_token.is_none() {
+            renewed.refresh_token = Some(refresh_token);
+        }
+
+        *token = renewed;
+
+        if let Some(path) = &self.path {
+            token.save(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Signs in with the device code flow when `device` is set, with the
+/// authorization code flow otherwise, and saves the tokens.
+pub async fn login(
+    settings: &OAuthSettings,
+    device: bool,
+    port: u16,
+    profile: Option<&str>,
+) -> Result<PathBuf> {
+    let client = OAuthClient::new(settings)?;
+    let response = if device {
+        client.device_code().await?
+    } else {
+        client.authorization_code(port).await?
+    };
+
+    if response.refresh_token.is_none() {
+        warn!("No refresh token was issued. Run login again when the token expires");
+    }
+
+    let path = match StoredToken::default_path(profile) {
+        Some(it) => it,
+        None => {
+            return Err(YammerError::OAuth {
+                error: "no_config_dir".to_owned(),
+                description: "Could not find the home directory to save the tokens".to_owned(),
+            }
+            .into())
+        }
+    };
+    StoredToken::new(&client, response).save(&path)?;
+    Ok(path)
+}
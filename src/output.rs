@@ -0,0 +1,434 @@
+use clap::ValueEnum;
+use rustmix::{AppInfo, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use crate::common::*;
+
+const TABLE_CELL_WIDTH: usize = 60;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// A JSON array.
+    #[default]
+    Json,
+    /// One JSON document per line.
+    Ndjson,
+    /// Comma separated values with a header row.
+    Csv,
+    /// An aligned text table.
+    Table,
+    /// A Markdown table.
+    Markdown,
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "json" => Some(OutputFormat::Json),
+            "ndjson" | "jsonl" => Some(OutputFormat::Ndjson),
+            "csv" => Some(OutputFormat::Csv),
+            "md" | "markdown" => Some(OutputFormat::Markdown),
+            "txt" => Some(OutputFormat::Table),
+            _ => None,
+        }
+    }
+}
+
+/// An item that can be written by an `OutputWriter`. The JSON formats use
+/// the serde representation, the tabular formats use `columns` and `rows`.
+pub trait Record: Serialize {
+    fn columns(&self) -> &'static [&'static str];
+    fn rows(&self) -> Vec<Vec<String>>;
+}
+
+impl Record for YammerUser {
+    fn columns(&self) -> &'static [&'static str] {
+        &["id", "name", "email", "network_id", "state", "job_title"]
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        vec![vec![
+            self.id.to_string(),
+            self.name.clone(),
+            self.email.clone(),
+            self.network_id.to_string(),
+            self.state.clone(),
+            self.job_title.clone(),
+        ]]
+    }
+}
+
+impl Record for YammerGroup {
+    fn columns(&self) -> &'static [&'static str] {
+        &["id", "name", "display_name"]
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        vec![vec![
+            self.id.to_string(),
+            self.name.clone(),
+            self.display_name.clone(),
+        ]]
+    }
+}
+
+impl Record for YammerTopic {
+    fn columns(&self) -> &'static [&'static str] {
+        &["id", "name"]
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        vec![vec![self.id.to_string(), self.name.clone()]]
+    }
+}
+
+const MESSAGE_COLUMNS: &[&str] = &[
+    "id",
+    "replied_to_id",
+    "thread_id",
+    "sender_id",
+    "sender_name",
+    "group_id",
+    "group_name",
+    "privacy",
+    "created_at",
+    "liked_by",
+    "body",
+];
+
+impl Record for YammerMessage {
+    fn columns(&self) -> &'static [&'static str] {
+        MESSAGE_COLUMNS
+    }
+
+    /// The message followed by all of its replies, depth first.
+    fn rows(&self) -> Vec<Vec<String>> {
+        let mut rows = vec![message_row(self)];
+
+        if let Some(replies) = &self.replies {
+            for reply in replies {
+                rows.extend(reply.rows());
+            }
+        }
+
+        rows
+    }
+}
+
+impl Record for PlanEntry {
+    fn columns(&self) -> &'static [&'static str] {
+        &[
+            "action",
+            "reason",
+            "id",
+            "thread_id",
+            "sender_id",
+            "sender_name",
+            "group_id",
+            "group_name",
+            "created_at",
+            "liked_by",
+            "body",
+        ]
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        let action = match self.action {
+            PlanAction::Delete => "delete",
+            PlanAction::Keep => "keep",
+        };
+        let message = &self.message;
+        vec![vec![
+            action.to_owned(),
+            self.reason.clone(),
+            message.id.to_string(),
+            message.thread_id.to_string(),
+            message.sender_id.to_string(),
+            message.sender_name.clone(),
+            message.group_id.to_string(),
+            message.group_name.clone(),
+            message.created_at.clone(),
+            message.liked_by.to_string(),
+            message.body.clone(),
+        ]]
+    }
+}
+
+impl Record for PostReport {
+    fn columns(&self) -> &'static [&'static str] {
+        &[
+            "row",
+            "status",
+            "group_id",
+            "replied_to_id",
+            "to",
+            "message_id",
+            "body",
+            "error",
+        ]
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        let status = match self.status {
+            PostStatus::Posted => "posted",
+            PostStatus::Planned => "planned",
+            PostStatus::Failed => "failed",
+        };
+        vec![vec![
+            self.row.to_string(),
+            status.to_owned(),
+            self.group_id.map(|e| e.to_string()).unwrap_or_default(),
+            self.replied_to_id
+                .map(|e| e.to_string())
+                .unwrap_or_default(),
+            self.to
+                .iter()
+                .map(|e| e.to_string())
+                .chain(self.unresolved.iter().cloned())
+                .collect::<Vec<_>>()
+                .join(";"),
+            self.message_id.map(|e| e.to_string()).unwrap_or_default(),
+            self.body.clone(),
+            self.error.clone().unwrap_or_default(),
+        ]]
+    }
+}
+
+fn message_row(message: &YammerMessage) -> Vec<String> {
+    vec![
+        message.id.to_string(),
+        message
+            .replied_to_id
+            .map(|e| e.to_string())
+            .unwrap_or_default(),
+        message.thread_id.to_string(),
+        message.sender_id.to_string(),
+        message.sender_name.clone(),
+        message.group_id.to_string(),
+        message.group_name.clone(),
+        message.privacy.clone(),
+        message.created_at.clone(),
+        message.liked_by.to_string(),
+        message.body.clone(),
+    ]
+}
+
+struct WriterState {
+    sink: Box<dyn Write + Send>,
+    count: usize,
+    columns: &'static [&'static str],
+    rows: Vec<Vec<String>>,
+}
+
+/// Writes the results of an action in the selected format. JSON, NDJSON, CSV
+/// and Markdown are streamed as records arrive; tables are buffered until the
+/// columns change or `finish` is called because the column widths depend on
+/// every row.
+pub struct OutputWriter {
+    format: OutputFormat,
+    state: Mutex<WriterState>,
+}
+
+impl OutputWriter {
+    pub fn new(format: OutputFormat) -> Self {
+        Self::with_sink(format, Box::new(io::stdout()))
+    }
+
+    pub fn create(format: OutputFormat, path: &Path) -> Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::with_sink(format, Box::new(BufWriter::new(file))))
+    }
+
+    pub fn with_sink(format: OutputFormat, sink: Box<dyn Write + Send>) -> Self {
+        Self {
+            format,
+            state: Mutex::new(WriterState {
+                sink,
+                count: 0,
+                columns: &[],
+                rows: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn write<T: Record>(&self, item: &T) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        match self.format {
+            OutputFormat::Json => {
+                let json = serde_json::to_string_pretty(item)?;
+                let prefix = if state.count == 0 { "[\n" } else { ",\n" };
+                write!(state.sink, "{}{}", prefix, json)?;
+            }
+            OutputFormat::Ndjson => {
+                let json = serde_json::to_string(item)?;
+                writeln!(state.sink, "{}", json)?;
+            }
+            OutputFormat::Csv => {
+                let header = state.columns != item.columns();
+
+                if header {
+                    if state.count > 0 {
+                        writeln!(state.sink)?;
+                    }
+                    state.columns = item.columns();
+                }
+
+                let mut writer = csv::Writer::from_writer(&mut state.sink);
+
+                if header {
+                    writer.write_record(item.columns())?;
+                }
+
+                for row in item.rows() {
+                    writer.write_record(&row)?;
+                }
+
+                writer.flush()?;
+            }
+            OutputFormat::Markdown => {
+                if state.columns != item.columns() {
+                    if state.count > 0 {
+                        writeln!(state.sink)?;
+                    }
+                    state.columns = item.columns();
+                    let columns = state.columns;
+                    writeln!(state.sink, "| {} |", columns.join(" | "))?;
+                    writeln!(state.sink, "|{}", "---|".repeat(columns.len()))?;
+                }
+
+                for row in item.rows() {
+                    let cells: Vec<String> = row.iter().map(|e| markdown_cell(e)).collect();
+                    writeln!(state.sink, "| {} |", cells.join(" | "))?;
+                }
+            }
+            OutputFormat::Table => {
+                if state.columns != item.columns() {
+                    flush_table(&mut state)?;
+                    state.columns = item.columns();
+                }
+
+                state.rows.extend(item.rows());
+            }
+        }
+
+        state.count += 1;
+        Ok(())
+    }
+
+    /// Closes any open document. Must be called once after the last record.
+    pub fn finish(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        match self.format {
+            OutputFormat::Json => {
+                if state.count == 0 {
+                    writeln!(state.sink, "[]")?;
+                } else {
+                    writeln!(state.sink, "\n]")?;
+                }
+            }
+            OutputFormat::Table => flush_table(&mut state)?,
+            _ => {}
+        }
+
+        state.sink.flush()?;
+        Ok(())
+    }
+}
+
+fn flush_table(state: &mut WriterState) -> Result<()> {
+    if state.rows.is_empty() {
+        return Ok(());
+    }
+
+    let rows: Vec<Vec<String>> = state
+        .rows
+        .drain(..)
+        .map(|row| row.iter().map(|e| table_cell(e)).collect())
+        .collect();
+    let mut widths: Vec<usize> = state.columns.iter().map(|e| e.chars().count()).collect();
+
+    for row in &rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+
+    let separator = format!(
+        "+{}+",
+        widths
+            .iter()
+            .map(|w| "-".repeat(w + 2))
+            .collect::<Vec<_>>()
+            .join("+")
+    );
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!(" {:<w$} ", cell, w = w))
+            .collect();
+        format!("|{}|", cells.join("|"))
+    };
+    writeln!(state.sink, "{}", separator)?;
+    writeln!(state.sink, "{}", line(state.columns.to_vec()))?;
+    writeln!(state.sink, "{}", separator)?;
+
+    for row in &rows {
+        writeln!(
+            state.sink,
+            "{}",
+            line(row.iter().map(|e| e.as_str()).collect())
+        )?;
+    }
+
+    writeln!(state.sink, "{}", separator)?;
+    Ok(())
+}
+
+fn markdown_cell(cell: &str) -> String {
+    cell.replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+}
+
+fn table_cell(cell: &str) -> String {
+    let cell = cell.replace(['\r', '\n'], " ");
+
+    if cell.chars().count() <= TABLE_CELL_WIDTH {
+        return cell;
+    }
+
+    let mut cell: String = cell.chars().take(TABLE_CELL_WIDTH - 1).collect();
+    cell.push('…');
+    cell
+}
+
+/// The banner goes to stderr so it never mixes with the results.
+pub fn print_header(appinfo: &AppInfo) {
+    eprintln!(
+        r#"
+★····························································★
+      _____   ° _________   _.★    ·               _____.·★
+    ·/  _  \ ★ /   _____/_/  |_  _______ ¤__ __★  /     \  ·
+  ★ /  /_\  \  \_____  \ \   ___\\_  __ \|  |  \ /  \ /  \·
+ · /    |    \ /        \ |  | ·  |  | \/|  |  //    Y    \ ★
+   \____|____//_________/ |__|  · |__|★  |____/ \____|____/·
+  ★·.°      ¤        °·★                ¤··•      ★·
+★····························································★
+·★·.·´¯`·.·★ {} v{} ★·.·´¯`·.·★
+·.•°¤*(¯`★´¯)*¤° {} °¤*)¯´★`¯(*¤°•.
+·★ {} ★·
+"#,
+        appinfo.name, appinfo.version, appinfo.authors, appinfo.description
+    );
+}
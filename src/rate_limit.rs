@@ -0,0 +1,385 @@
+use log::{debug, info};
+use rustmix::web::reqwest::{Method, Url};
+use serde::Deserialize;
+use std::{fmt, str::FromStr, time::Duration, time::Instant};
+use tokio::{sync::Mutex, time::sleep};
+
+/// The endpoint classes Yammer throttles separately. Listing messages has a
+/// tighter limit than the rest of the REST API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EndpointClass {
+    Read,
+    Write,
+    Messages,
+    Autocomplete,
+}
+
+impl EndpointClass {
+    pub fn of(method: &Method, url: &Url) -> Self {
+        let path = url.path();
+
+        if path.contains("/autocomplete") {
+            EndpointClass::Autocomplete
+        } else if *method != Method::GET && *method != Method::HEAD {
+            EndpointClass::Write
+        } else if path.contains("/messages") {
+            EndpointClass::Messages
+        } else {
+            EndpointClass::Read
+        }
+    }
+}
+
+impl fmt::Display for EndpointClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EndpointClass::Read => "read",
+            EndpointClass::Write => "write",
+            EndpointClass::Messages => "messages",
+            EndpointClass::Autocomplete => "autocomplete",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A number of requests per period, written as `<requests>/<period>`, e.g.
+/// `10/10s` or `10/30s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    pub const fn new(requests: u32, period: Duration) -> Self {
+        Self { requests, period }
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (requests, period) = s
+            .split_once('/')
+            .ok_or_else(|| format!("Expected <requests>/<period>, e.g. 10/10s, got '{}'", s))?;
+        let requests: u32 = requests
+            .trim()
+            .parse()
+            .map_err(|e| format!("Invalid number of requests '{}': {}", requests, e))?;
+        let period = humantime::parse_duration(period.trim())
+            .map_err(|e| format!("Invalid period '{}': {}", period, e))?;
+
+        if requests == 0 || period.is_zero() {
+            return Err("The number of requests and the period must be greater than 0".to_owned());
+        }
+
+        Ok(Self::new(requests, period))
+    }
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.requests,
+            humantime::format_duration(self.period)
+        )
+    }
+}
+
+/// The limits of every endpoint class. The defaults are Yammer's documented
+/// limits: 10 requests in 30 seconds for messages and 10 requests in 10
+/// seconds for everything else. When `adaptive` is set, the limits are the
+/// highest rates and the buckets slow down while the API answers with 429.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub read: RateLimit,
+    pub write: RateLimit,
+    pub messages: RateLimit,
+    pub autocomplete: RateLimit,
+    pub adaptive: bool,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            read: RateLimit::new(10, Duration::from_secs(10)),
+            write: RateLimit::new(10, Duration::from_secs(10)),
+            messages: RateLimit::new(10, Duration::from_secs(30)),
+            autocomplete: RateLimit::new(10, Duration::from_secs(10)),
+            adaptive: true,
+        }
+    }
+}
+
+/// The number of successful requests in a row before an adaptive bucket
+/// raises its rate again.
+const SUCCESS_STREAK: u32 = 10;
+/// The lowest rate of an adaptive bucket, as a fraction of its limit.
+const MIN_RATE_FACTOR: f64 = 1.0 / 16.0;
+/// The rate an adaptive bucket gains after a streak, as a fraction of its limit.
+const RATE_STEP_FACTOR: f64 = 1.0 / 10.0;
+
+/// A token bucket with a sub-second refill. An adaptive bucket lowers its
+/// rate multiplicatively on 429 and raises it additively after a streak of
+/// successful requests, never above the configured limit (AIMD).
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    tokens_per_second: f64,
+    max_rate: f64,
+    adaptive: bool,
+    successes: u32,
+    decreased: Option<Instant>,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket that refills `limit.requests` tokens evenly over `limit.period`.
+    pub fn new(limit: RateLimit, adaptive: bool) -> Self {
+        let capacity = limit.requests as f64;
+        let rate = capacity / limit.period.as_secs_f64();
+        Self {
+            capacity,
+            tokens: capacity,
+            tokens_per_second: rate,
+            max_rate: rate,
+            adaptive,
+            successes: 0,
+            decreased: None,
+            updated: Instant::now(),
+        }
+    }
+
+    /// The current refill rate in requests per second.
+    pub fn rate(&self) -> f64 {
+        self.tokens_per_second
+    }
+
+    pub fn take(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until the next token is available.
+    pub fn wait_time(&self) -> Duration {
+        let missing = (1.0 - self.tokens).max(0.0);
+        Duration::from_secs_f64(missing / self.tokens_per_second)
+    }
+
+    /// Halves the rate and empties the bucket. 429s received while the
+    /// bucket refills after a decrease belong to the same burst and are
+    /// ignored. Returns the new rate if it changed.
+    pub fn decrease(&mut self) -> Option<f64> {
+        if !self.adaptive {
+            return None;
+        }
+
+        self.successes = 0;
+
+        if let Some(decreased) = self.decreased {
+            let window = Duration::from_secs_f64(self.capacity / self.tokens_per_second);
+
+            if decreased.elapsed() < window {
+                return None;
+            }
+        }
+
+        self.refill();
+        self.tokens = 0.0;
+        self.decreased = Some(Instant::now());
+        let rate = (self.tokens_per_second / 2.0).max(self.max_rate * MIN_RATE_FACTOR);
+
+        if rate == self.tokens_per_second {
+            return None;
+        }
+
+        self.tokens_per_second = rate;
+        Some(rate)
+    }
+
+    /// Counts a successful request and raises the rate after a streak.
+    /// Returns the new rate if it changed.
+    pub fn increase(&mut self) -> Option<f64> {
+        if !self.adaptive || self.tokens_per_second >= self.max_rate {
+            return None;
+        }
+
+        self.successes += 1;
+
+        if self.successes < SUCCESS_STREAK {
+            return None;
+        }
+
+        self.refill();
+        self.successes = 0;
+        self.tokens_per_second =
+            (self.tokens_per_second + self.max_rate * RATE_STEP_FACTOR).min(self.max_rate);
+        Some(self.tokens_per_second)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.tokens_per_second).min(self.capacity);
+    }
+}
+
+/// One token bucket per endpoint class, shared by every clone of a `Service`.
+#[derive(Debug)]
+pub struct RateLimiter {
+    read: Mutex<TokenBucket>,
+    write: Mutex<TokenBucket>,
+    messages: Mutex<TokenBucket>,
+    autocomplete: Mutex<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &RateLimits) -> Self {
+        Self {
+            read: Mutex::new(TokenBucket::new(limits.read, limits.adaptive)),
+            write: Mutex::new(TokenBucket::new(limits.write, limits.adaptive)),
+            messages: Mutex::new(TokenBucket::new(limits.messages, limits.adaptive)),
+            autocomplete: Mutex::new(TokenBucket::new(limits.autocomplete, limits.adaptive)),
+        }
+    }
+
+    /// Waits until a token of the class is available and takes it.
+    pub async fn acquire(&self, class: EndpointClass) {
+        let bucket = self.bucket(class);
+
+        loop {
+            let wait = {
+                let mut bkt = bucket.lock().await;
+
+                if bkt.take() {
+                    return;
+                }
+
+                bkt.wait_time()
+            };
+            debug!(
+                "Waiting {} for a {} token",
+                humantime::format_duration(wait),
+                class
+            );
+            sleep(wait).await;
+        }
+    }
+
+    pub async fn rate_limited(&self, class: EndpointClass) {
+        if let Some(rate) = self.bucket(class).lock().await.decrease() {
+            info!(
+                "Lowering the {} rate to {:.2} requests per second",
+                class, rate
+            );
+        }
+    }
+
+    pub async fn succeeded(&self, class: EndpointClass) {
+        if let Some(rate) = self.bucket(class).lock().await.increase() {
+            info!(
+                "Raising the {} rate to {:.2} requests per second",
+                class, rate
+            );
+        }
+    }
+
+    /// The current rates of all the classes, for the end of run report.
+    pub async fn rates(&self) -> String {
+        let mut rates = Vec::new();
+
+        for class in [
+            EndpointClass::Read,
+            EndpointClass::Write,
+            EndpointClass::Messages,
+            EndpointClass::Autocomplete,
+        ] {
+            let rate = self.bucket(class).lock().await.rate();
+            rates.push(format!("{} {:.2}/s", class, rate));
+        }
+
+        rates.join(", ")
+    }
+
+    fn bucket(&self, class: EndpointClass) -> &Mutex<TokenBucket> {
+        match class {
+            EndpointClass::Read => &self.read,
+            EndpointClass::Write => &self.write,
+            EndpointClass::Messages => &self.messages,
+            EndpointClass::Autocomplete => &self.autocomplete,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_is_parsed() {
+        let limit: RateLimit = "10/30s".parse().unwrap();
+        assert_eq!(limit, RateLimit::new(10, Duration::from_secs(30)));
+        let limit: RateLimit = " 5 / 1m ".parse().unwrap();
+        assert_eq!(limit, RateLimit::new(5, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn invalid_rate_limits_are_refused() {
+        for value in ["10", "", "x/10s", "-1/10s", "10/forever", "0/10s", "10/0s"] {
+            assert!(value.parse::<RateLimit>().is_err(), "'{}'", value);
+        }
+    }
+
+    #[test]
+    fn bucket_halves_on_429_and_recovers_additively() {
+        let mut bucket = TokenBucket::new(RateLimit::new(10, Duration::from_secs(10)), true);
+        assert_eq!(bucket.rate(), 1.0);
+
+        assert_eq!(bucket.decrease(), Some(0.5));
+        // A 429 of the same burst does not lower the rate again
+        assert_eq!(bucket.decrease(), None);
+        assert!(!bucket.take());
+
+        for _ in 1..SUCCESS_STREAK {
+            assert_eq!(bucket.increase(), None);
+        }
+
+        let rate = bucket.increase().unwrap();
+        assert!((rate - 0.6).abs() < 1e-9);
+
+        for _ in 0..SUCCESS_STREAK * 10 {
+            bucket.increase();
+        }
+
+        assert_eq!(bucket.rate(), 1.0);
+        assert_eq!(bucket.increase(), None);
+    }
+
+    #[test]
+    fn fixed_bucket_ignores_429() {
+        let mut bucket = TokenBucket::new(RateLimit::new(10, Duration::from_secs(10)), false);
+        assert_eq!(bucket.decrease(), None);
+        assert_eq!(bucket.rate(), 1.0);
+        assert!(bucket.take());
+    }
+}
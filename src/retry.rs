@@ -0,0 +1,194 @@
+use chrono::{DateTime, Utc};
+use rustmix::{random, web::reqwest::Method};
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// How `Service` retries a request that failed with a transient error.
+/// 429s are retried for every method because the request was never handled;
+/// 502, 503, 504, timeouts and connection errors only for idempotent methods.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The number of retries after the first attempt.
+    pub max_retries: u32,
+    /// The delay of the first retry. It doubles on every following retry.
+    pub base_delay: Duration,
+    /// The upper bound of a single delay, including the one from Retry-After.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            max_retries,
+            base_delay,
+            max_delay: max_delay.max(base_delay),
+        }
+    }
+
+    pub fn is_retryable_method(method: &Method) -> bool {
+        matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+    }
+
+    pub fn is_retryable_status(status: u16) -> bool {
+        matches!(status, 502 | 503 | 504)
+    }
+
+    /// The delay before retry number `attempt`, starting at 0. The server's
+    /// Retry-After wins when present; otherwise the delay is picked at random
+    /// between half and all of the exponential backoff so concurrent clients
+    /// do not retry in lockstep.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let ms = backoff.as_millis() as u64;
+
+        if ms < 2 {
+            return backoff;
+        }
+
+        Duration::from_millis(random::numeric(ms / 2..=ms))
+    }
+}
+
+/// Parses a Retry-After value, either delay-seconds or an HTTP date.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let seconds = (date.with_timezone(&Utc) - Utc::now()).num_seconds().max(0);
+    Some(Duration::from_secs(seconds as u64))
+}
+
+/// Counters of the retries made by a `Service`, reported at the end of a run.
+#[derive(Debug, Default)]
+pub struct RetryStats {
+    requests: AtomicU64,
+    retries: AtomicU64,
+    rate_limited: AtomicU64,
+    server_errors: AtomicU64,
+    network_errors: AtomicU64,
+    exhausted: AtomicU64,
+    waited_ms: AtomicU64,
+}
+
+impl RetryStats {
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rate_limited(&self, delay: Duration) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+        self.retry(delay);
+    }
+
+    pub fn server_error(&self, delay: Duration) {
+        self.server_errors.fetch_add(1, Ordering::Relaxed);
+        self.retry(delay);
+    }
+
+    pub fn network_error(&self, delay: Duration) {
+        self.network_errors.fetch_add(1, Ordering::Relaxed);
+        self.retry(delay);
+    }
+
+    pub fn exhausted(&self) {
+        self.exhausted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn retry(&self, delay: Duration) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        self.waited_ms
+            .fetch_add(delay.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for RetryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests, {} retries ({} rate limited, {} server errors, {} network errors), {} gave up, waited {}",
+            self.requests.load(Ordering::Relaxed),
+            self.retries.load(Ordering::Relaxed),
+            self.rate_limited.load(Ordering::Relaxed),
+            self.server_errors.load(Ordering::Relaxed),
+            self.network_errors.load(Ordering::Relaxed),
+            self.exhausted.load(Ordering::Relaxed),
+            humantime::format_duration(Duration::from_millis(
+                self.waited_ms.load(Ordering::Relaxed)
+            )),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("0"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_as_http_date() {
+        let date = (Utc::now() + ChronoDuration::seconds(90)).to_rfc2822();
+        let delay = parse_retry_after(&date).unwrap();
+        assert!(delay <= Duration::from_secs(90));
+        assert!(delay >= Duration::from_secs(88));
+
+        let past = parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(past, Duration::ZERO);
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        let policy = RetryPolicy::new(3, Duration::from_secs(1), Duration::from_secs(10));
+        let delay = policy.delay(0, Some(Duration::from_secs(120)));
+        assert_eq!(delay, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn jitter_stays_within_half_of_the_backoff() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(100), Duration::from_secs(1));
+
+        for attempt in 0..6 {
+            let ms = (100u64 << attempt).min(1000);
+
+            for _ in 0..50 {
+                let delay = policy.delay(attempt, None).as_millis() as u64;
+                assert!(
+                    (ms / 2..=ms).contains(&delay),
+                    "attempt {}: {}ms",
+                    attempt,
+                    delay
+                );
+            }
+        }
+    }
+}
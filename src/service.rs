@@ -1,33 +1,123 @@
 #![allow(dead_code)]
+use futures::{stream, StreamExt};
+use humantime::format_duration;
 use log::{error, info, warn};
 use reqwest_cookie_store::{CookieStore, CookieStoreRwLock};
 use rustmix::{
     error::*,
-    web::reqwest::{Client, RequestBuilder, Response},
+    web::reqwest::{
+        header::{self, HeaderValue},
+        Client, Request, RequestBuilder, Response,
+    },
     *,
 };
-use serde_json::Value;
+use serde::de::DeserializeOwned;
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
     sync::Arc,
     time::Duration,
 };
 use tokio::{sync::Mutex, time::sleep};
+use url::Url;
 
-use crate::common::*;
+use crate::{
+    api::{ApiGroup, ApiMessage, ApiMessages, ApiNewMessage, ApiReference, ApiSearch, ApiUser},
+    archive::Archive,
+    common::*,
+    error::{is_not_found, YammerError},
+    fixture::{FixtureMode, Fixtures},
+    journal::Journal,
+    oauth::TokenRefresher,
+    output::OutputWriter,
+    rate_limit::{EndpointClass, RateLimiter, RateLimits},
+    retry::{parse_retry_after, RetryPolicy, RetryStats},
+};
+
+pub const DEFAULT_BASE_URL: &str = "https://www.yammer.com/api/v1/";
+
+/// State shared by a single delete run. When `plan` is set, nothing is
+/// deleted and every visited message is recorded in the plan instead.
+pub struct DeleteContext {
+    pub output: Arc<OutputWriter>,
+    pub plan: Option<Vec<PlanEntry>>,
+    pub journal: Option<Journal>,
+    pub archive: Option<Archive>,
+    /// The number of messages the API refused to delete.
+    pub failed: u64,
+    /// The number of threads processed at the same time.
+    pub concurrency: usize,
+}
+
+impl DeleteContext {
+    pub fn new(output: Arc<OutputWriter>) -> Self {
+        Self {
+            output,
+            plan: None,
+            journal: None,
+            archive: None,
+            failed: 0,
+            concurrency: 1,
+        }
+    }
+
+    pub fn is_finished(&self, thread_id: u64) -> bool {
+        self.journal
+            .as_ref()
+            .map(|e| e.is_finished(thread_id))
+            .unwrap_or(false)
+    }
+}
+
+/// State shared by a single redact run. When `dry_run` is set, nothing is
+/// changed and the messages that would be redacted are written instead.
+pub struct RedactContext {
+    pub output: Arc<OutputWriter>,
+    /// The text that replaces the message bodies.
+    pub placeholder: String,
+    pub dry_run: bool,
+    /// The number of messages the API refused to update.
+    pub failed: u64,
+}
+
+impl RedactContext {
+    pub fn new(output: Arc<OutputWriter>, placeholder: &str) -> Self {
+        Self {
+            output,
+            placeholder: placeholder.to_owned(),
+            dry_run: false,
+            failed: 0,
+        }
+    }
+}
 
-const BASE_URL: &str = "https://www.yammer.com/api/v1/";
-const RLT_10: u64 = 10;
-const RLT_30: u64 = 30;
+/// The messages a thread produced, as (action, reason, message). Without a
+/// plan, only the deleted messages are recorded.
+#[derive(Default)]
+struct ThreadResult {
+    count: u64,
+    entries: Vec<(PlanAction, String, ApiMessage)>,
+}
+
+/// A listed message and what became of its thread.
+enum ThreadTask {
+    Finished(ApiMessage),
+    Skipped(ApiMessage, &'static str),
+    Done(ApiMessage, Result<ThreadResult>),
+}
 
 #[derive(Debug, Clone)]
 pub struct Service {
+    base_url: String,
     client: Arc<Client>,
-    bucket: Arc<Mutex<TokenBucket>>,
+    limiter: Arc<RateLimiter>,
+    retry: RetryPolicy,
+    stats: Arc<RetryStats>,
+    auth: Option<Arc<TokenRefresher>>,
+    fixtures: Option<Arc<Fixtures>>,
 }
 
 impl Service {
-    pub fn new() -> Self {
+    pub fn new(base_url: &str, retry: RetryPolicy, limits: RateLimits) -> Self {
         let cookies = Arc::new(CookieStoreRwLock::new(CookieStore::default()));
         let client = match build_compatible_client(&cookies) {
             Ok(it) => Arc::new(it),
@@ -35,41 +125,63 @@ impl Service {
                 panic!("Error building client: {}", e.get_message());
             }
         };
-        let bucket = Arc::new(Mutex::new(TokenBucket::new(10, 30)));
-        Self { client, bucket }
+        info!(
+            "Rate limits: read {}, write {}, messages {}, autocomplete {}",
+            limits.read, limits.write, limits.messages, limits.autocomplete
+        );
+        let limiter = Arc::new(RateLimiter::new(&limits));
+        Self {
+            base_url: base_url.to_owned(),
+            client,
+            limiter,
+            retry,
+            stats: Arc::new(RetryStats::default()),
+            auth: None,
+            fixtures: None,
+        }
+    }
+
+    /// Renews the token on expiry or 401 and replays the request. The token
+    /// passed to the other methods is then replaced by the current one.
+    pub fn with_token_refresher(mut self, refresher: TokenRefresher) -> Self {
+        self.auth = Some(Arc::new(refresher));
+        self
+    }
+
+    /// Records the exchanges to, or replays them from, a fixtures directory.
+    pub fn with_fixtures(mut self, fixtures: Fixtures) -> Self {
+        self.fixtures = Some(Arc::new(fixtures));
+        self
+    }
+
+    pub fn retry_stats(&self) -> &RetryStats {
+        &self.stats
+    }
+
+    /// The effective rates of the rate limiter.
+    pub async fn rates(&self) -> String {
+        self.limiter.rates().await
     }
 
     pub async fn get_user_id(&self, token: &str, user_email: &str) -> Result<u64> {
         if user_email.is_empty() {
-            return Err(InvalidEmailError.into());
+            return Err(YammerError::InvalidArgument {
+                name: "email".to_owned(),
+            }
+            .into());
         }
 
         info!("Fetching user information for email '{}'", user_email);
         let url = format!(
             "{}users/by_email.json?email={}",
-            BASE_URL,
+            self.base_url,
             urlencoding::encode(&user_email)
         );
-        let response = self
-            .send_with_rate_limit(
-                self.client
-                    .get(&url)
-                    .header("authorization", format!("Bearer {}", &token)),
-                RLT_10,
-            )
-            .await?;
-        let text = self.get_json_text(response).await?;
-        let json = match serde_json::from_str::<Value>(&text) {
-            Ok(it) => it,
-            Err(e) => {
-                error!("{}\n{}", e, text);
-                return Err(e.into());
-            }
-        };
-        let id = json
-            .as_array()
-            .and_then(|items| items.iter().find(|u| u["type"] == "user"))
-            .and_then(|user| user["id"].as_u64());
+        let users: Vec<ApiUser> = self.get_json(token, &url).await?.unwrap_or_default();
+        let id = users
+            .iter()
+            .find(|u| u.kind.as_deref() == Some("user"))
+            .map(|u| u.id);
 
         if let Some(id) = id {
             info!("User id for email '{}' is found: {}", user_email, id);
@@ -77,29 +189,28 @@ impl Service {
         }
 
         warn!("User id for email '{}' is not found", user_email);
-        return Err(InvalidEmailError.into());
+        return Err(YammerError::NotFound.into());
+    }
+
+    /// The user the token belongs to.
+    pub async fn get_current_user(&self, token: &str) -> Result<YammerUser> {
+        info!("Fetching the current user");
+        let url = format!("{}users/current.json", self.base_url);
+        let user: ApiUser = match self.get_json(token, &url).await? {
+            Some(it) => it,
+            None => return Err(YammerError::NotFound.into()),
+        };
+        Ok(YammerUser::from_api(&user))
     }
 
     pub async fn get_user_info(&self, token: &str, user_id: u64) -> Result<YammerUser> {
         info!("Fetching user information for id '{}'", user_id);
-        let url = format!("{}users/{}.json", BASE_URL, user_id);
-        let response = self
-            .send_with_rate_limit(
-                self.client
-                    .get(&url)
-                    .header("authorization", format!("Bearer {}", &token)),
-                RLT_10,
-            )
-            .await?;
-        let text = self.get_json_text(response).await?;
-        let json = match serde_json::from_str::<Value>(&text) {
-            Ok(it) => it,
-            Err(e) => {
-                error!("{}\n{}", e, text);
-                return Err(e.into());
-            }
+        let url = format!("{}users/{}.json", self.base_url, user_id);
+        let user: ApiUser = match self.get_json(token, &url).await? {
+            Some(it) => it,
+            None => return Err(YammerError::NotFound.into()),
         };
-        Ok(YammerUser::from_json(&json))
+        Ok(YammerUser::from_api(&user))
     }
 
     pub async fn get_users<C>(
@@ -118,37 +229,20 @@ impl Service {
         );
         let url = format!(
             "{}users.json?page={}&num_per_page={}",
-            BASE_URL, page, num_per_page
+            self.base_url, page, num_per_page
         );
-        let response = self
-            .send_with_rate_limit(
-                self.client
-                    .get(&url)
-                    .header("authorization", format!("Bearer {}", &token)),
-                RLT_10,
-            )
-            .await?;
-        let text = self.get_json_text(response).await?;
-        if text.is_empty() {
-            return Ok(false);
-        }
-        let json = match serde_json::from_str::<Value>(&text) {
-            Ok(it) => it,
-            Err(e) => {
-                error!("{}\n{}", e, text);
-                return Err(e.into());
-            }
+        let items: Vec<ApiUser> = match self.get_json(token, &url).await? {
+            Some(it) => it,
+            None => return Ok(false),
         };
-        let users = json
-            .as_array()
-            .unwrap()
+        let items = items
             .iter()
-            .filter(|e| e["type"] == "user")
+            .filter(|e| e.kind.as_deref() == Some("user"))
             .map(|e| {
-                let user = YammerUser::from_json(e);
-                (user.id, user)
+                let item = YammerUser::from_api(e);
+                (item.id, item)
             });
-        collection.extend(users);
+        collection.extend(items);
         Ok(true)
     }
 
@@ -162,36 +256,19 @@ impl Service {
         C: Extend<(u64, YammerGroup)> + Send,
     {
         info!("Fetching groups for user '{}'", user_id);
-        let url = format!("{}groups/for_user/{}.json", BASE_URL, &user_id);
-        let response = self
-            .send_with_rate_limit(
-                self.client
-                    .get(&url)
-                    .header("authorization", format!("Bearer {}", &token)),
-                RLT_10,
-            )
-            .await?;
-        let text = self.get_json_text(response).await?;
-        if text.is_empty() {
-            return Ok(false);
-        }
-        let json = match serde_json::from_str::<Value>(&text) {
-            Ok(it) => it,
-            Err(e) => {
-                error!("{}\n{}", e, text);
-                return Err(e.into());
-            }
+        let url = format!("{}groups/for_user/{}.json", self.base_url, &user_id);
+        let items: Vec<ApiGroup> = match self.get_json(token, &url).await? {
+            Some(it) => it,
+            None => return Ok(false),
         };
-        let groups = json
-            .as_array()
-            .unwrap()
+        let items = items
             .iter()
-            .filter(|e| e["type"] == "group")
+            .filter(|e| e.kind.as_deref() == Some("group"))
             .map(|e| {
-                let group = YammerGroup::from_json(e);
-                (group.id, group)
+                let item = YammerGroup::from_api(e);
+                (item.id, item)
             });
-        collection.extend(groups);
+        collection.extend(items);
         Ok(true)
     }
 
@@ -206,36 +283,22 @@ impl Service {
         C: Extend<(u64, YammerUser)> + Send,
     {
         info!("Fetching users in group {} for page {}", group_id, page);
-        let url = format!("{}users/in_group/{}.json&page={}", BASE_URL, group_id, page);
-        let response = self
-            .send_with_rate_limit(
-                self.client
-                    .get(&url)
-                    .header("authorization", format!("Bearer {}", &token)),
-                RLT_10,
-            )
-            .await?;
-        let text = self.get_json_text(response).await?;
-        if text.is_empty() {
-            return Ok(false);
-        }
-        let json = match serde_json::from_str::<Value>(&text) {
-            Ok(it) => it,
-            Err(e) => {
-                error!("{}\n{}", e, text);
-                return Err(e.into());
-            }
+        let url = format!(
+            "{}users/in_group/{}.json&page={}",
+            self.base_url, group_id, page
+        );
+        let items: Vec<ApiUser> = match self.get_json(token, &url).await? {
+            Some(it) => it,
+            None => return Ok(false),
         };
-        let users = json
-            .as_array()
-            .unwrap()
+        let items = items
             .iter()
-            .filter(|e| e["type"] == "user")
+            .filter(|e| e.kind.as_deref() == Some("user"))
             .map(|e| {
-                let user = YammerUser::from_json(e);
-                (user.id, user)
+                let item = YammerUser::from_api(e);
+                (item.id, item)
             });
-        collection.extend(users);
+        collection.extend(items);
         Ok(true)
     }
 
@@ -248,7 +311,7 @@ impl Service {
         last_message_id: Option<u64>,
     ) -> Result<bool>
     where
-        C: Extend<Value> + Send,
+        C: Extend<ApiMessage> + Send,
     {
         info!("Fetching messages");
         let p_message = if let Some(lmid) = last_message_id {
@@ -259,42 +322,24 @@ impl Service {
         let url = if let Some(group_id) = group_id {
             format!(
                 "{}messages/in_group/{}.json?threaded=true{}",
-                BASE_URL, group_id, p_message
+                self.base_url, group_id, p_message
             )
         } else {
-            format!("{}messages/sent.json?threaded=true{}", BASE_URL, p_message)
-        };
-        let response = self
-            .send_with_rate_limit(
-                self.client
-                    .get(&url)
-                    .header("authorization", format!("Bearer {}", &token)),
-                RLT_10,
+            format!(
+                "{}messages/sent.json?threaded=true{}",
+                self.base_url, p_message
             )
-            .await?;
-        let text = self.get_json_text(response).await?;
-        if text.is_empty() {
-            return Ok(false);
-        }
-        let json = match serde_json::from_str::<Value>(&text) {
-            Ok(it) => it,
-            Err(e) => {
-                error!("{}\n{}", e, text);
-                return Err(e.into());
-            }
         };
-        let messages = json["messages"]
-            .as_array()
-            .unwrap()
-            .iter()
-            .filter(|e| {
-                e["sender_type"] == "user"
-                    && (user_id.is_none() || e["sender_id"].as_u64() == user_id)
-            })
-            .cloned();
+        let json: ApiMessages = match self.get_json(token, &url).await? {
+            Some(it) => it,
+            None => return Ok(false),
+        };
+        let messages = json
+            .messages
+            .into_iter()
+            .filter(|e| e.is_from_user() && (user_id.is_none() || e.sender_id == user_id));
         collection.extend(messages);
-        let older_available = json["meta"]["older_available"].as_bool().unwrap_or(false);
-        return Ok(older_available);
+        return Ok(json.meta.older_available);
     }
 
     pub async fn get_messages_in_thread<C>(
@@ -305,42 +350,130 @@ impl Service {
         user_id: Option<u64>,
     ) -> Result<()>
     where
-        C: Extend<Value> + Send,
+        C: Extend<ApiMessage> + Send,
     {
         info!("Fetching messages for thread {}", thread_id);
-        let url = format!("{}messages/in_thread/{}.json", BASE_URL, &thread_id);
-        let response = self
-            .send_with_rate_limit(
-                self.client
-                    .get(&url)
-                    .header("authorization", format!("Bearer {}", &token)),
-                RLT_10,
-            )
-            .await?;
-        let text = self.get_json_text(response).await?;
-        if text.is_empty() {
-            return Ok(());
-        }
-        let json = match serde_json::from_str::<Value>(&text) {
-            Ok(it) => it,
-            Err(e) => {
-                error!("{}\n{}", e, text);
-                return Err(e.into());
-            }
+        let url = format!("{}messages/in_thread/{}.json", self.base_url, &thread_id);
+        let json: ApiMessages = match self.get_json(token, &url).await? {
+            Some(it) => it,
+            None => return Ok(()),
         };
-        let messages = json["messages"]
-            .as_array()
-            .unwrap()
-            .iter()
-            .filter(|e| {
-                e["sender_type"] == "user"
-                    && (user_id.is_none() || e["sender_id"].as_u64() == user_id)
-            })
-            .cloned();
+        let messages = json
+            .messages
+            .into_iter()
+            .filter(|e| e.is_from_user() && (user_id.is_none() || e.sender_id == user_id));
         collection.extend(messages);
         return Ok(());
     }
 
+    pub async fn get_feed_messages<C>(
+        &self,
+        collection: &mut C,
+        token: &str,
+        last_message_id: Option<u64>,
+    ) -> Result<bool>
+    where
+        C: Extend<ApiMessage> + Send,
+    {
+        info!("Fetching feed messages");
+        let p_message = if let Some(lmid) = last_message_id {
+            format!("&older_than={}", lmid)
+        } else {
+            String::new()
+        };
+        let url = format!("{}messages.json?threaded=true{}", self.base_url, p_message);
+        let json: ApiMessages = match self.get_json(token, &url).await? {
+            Some(it) => it,
+            None => return Ok(false),
+        };
+        let messages = json.messages.into_iter().filter(|e| e.is_from_user());
+        collection.extend(messages);
+        return Ok(json.meta.older_available);
+    }
+
+    /// Fetches one page of the messages the user likes.
+    pub async fn get_liked_messages<C>(
+        &self,
+        collection: &mut C,
+        token: &str,
+        user_id: u64,
+        last_message_id: Option<u64>,
+    ) -> Result<bool>
+    where
+        C: Extend<ApiMessage> + Send,
+    {
+        info!("Fetching messages liked by user '{}'", user_id);
+        let p_message = if let Some(lmid) = last_message_id {
+            format!("?older_than={}", lmid)
+        } else {
+            String::new()
+        };
+        let url = format!(
+            "{}messages/liked_by/{}.json{}",
+            self.base_url, user_id, p_message
+        );
+        let json: ApiMessages = match self.get_json(token, &url).await? {
+            Some(it) => it,
+            None => return Ok(false),
+        };
+        collection.extend(json.messages);
+        return Ok(json.meta.older_available);
+    }
+
+    /// Fetches one page of a thread without filtering any message out.
+    pub async fn get_thread_page<C>(
+        &self,
+        collection: &mut C,
+        token: &str,
+        thread_id: u64,
+        last_message_id: Option<u64>,
+    ) -> Result<bool>
+    where
+        C: Extend<ApiMessage> + Send,
+    {
+        info!("Fetching messages page for thread {}", thread_id);
+        let p_message = if let Some(lmid) = last_message_id {
+            format!("?older_than={}", lmid)
+        } else {
+            String::new()
+        };
+        let url = format!(
+            "{}messages/in_thread/{}.json{}",
+            self.base_url, &thread_id, p_message
+        );
+        let json: ApiMessages = match self.get_json(token, &url).await? {
+            Some(it) => it,
+            None => return Ok(false),
+        };
+        let messages = json.messages.into_iter();
+        collection.extend(messages);
+        return Ok(json.meta.older_available);
+    }
+
+    /// Downloads an attachment. The token is only sent to the API host, so
+    /// files hosted elsewhere are fetched without credentials.
+    pub async fn download(&self, token: &str, url: &str) -> Result<Vec<u8>> {
+        info!("Downloading '{}'", url);
+        let mut request = self.client.get(url);
+
+        if self.is_api_host(url) {
+            request = request.header("authorization", format!("Bearer {}", &token));
+        }
+
+        let response = self.send_with_rate_limit(request).await?;
+        let response = self.error_for_status(response).await?;
+        let bytes = response.bytes().await.map_err(YammerError::Network)?;
+        Ok(bytes.to_vec())
+    }
+
+    /// Whether the URL has the scheme, host and port of the API base URL.
+    fn is_api_host(&self, url: &str) -> bool {
+        match (Url::parse(url), Url::parse(&self.base_url)) {
+            (Ok(url), Ok(base)) => url.origin() == base.origin(),
+            _ => false,
+        }
+    }
+
     pub async fn delete(
         &self,
         token: &str,
@@ -348,6 +481,7 @@ impl Service {
         thread_id: Option<u64>,
         user_id: Option<u64>,
         exclude: &HashSet<u64>,
+        ctx: &mut DeleteContext,
     ) -> Result<u64> {
         let mut groups = HashMap::new();
 
@@ -356,15 +490,31 @@ impl Service {
         }
 
         if let Some(thread_id) = thread_id {
-            return self
-                .delete_thread(token, thread_id, user_id, &mut groups)
-                .await;
+            if ctx.is_finished(thread_id) {
+                info!("Thread '{}' is already finished", thread_id);
+                return Ok(0);
+            }
+
+            let shared = Mutex::new(&mut *ctx);
+            let result = self
+                .delete_thread(token, thread_id, user_id, &shared)
+                .await?;
+            drop(shared);
+            let count = result.count;
+            write_thread_result(ctx, &groups, result)?;
+
+            if let Some(journal) = ctx.journal.as_mut() {
+                journal.finish_thread(thread_id)?;
+            }
+
+            return YammerError::partial(count, ctx.failed);
         }
 
         // rate limit already taken in get_messages
         let mut messages = VecDeque::new();
         let mut has_more = true;
-        let mut last_message_id = None;
+        let mut last_message_id = ctx.journal.as_ref().and_then(|e| e.older_than());
+        let mut queued = HashSet::new();
         let mut count = 0u64;
 
         if let Some(group_id) = group_id {
@@ -373,7 +523,16 @@ impl Service {
             info!("Fetching messages for deletion");
         }
 
+        if let Some(older_than) = last_message_id {
+            info!(
+                "Resuming deletion from messages older than '{}'",
+                older_than
+            );
+        }
+
         let uid = user_id.unwrap_or(0);
+        let concurrency = ctx.concurrency.max(1);
+        let shared = Mutex::new(&mut *ctx);
 
         while has_more {
             has_more = self
@@ -385,168 +544,919 @@ impl Service {
                     last_message_id,
                 )
                 .await?;
+            last_message_id = messages.back().map(|e| e.id).or(last_message_id);
 
-            while let Some(message) = messages.pop_front() {
-                last_message_id = message["id"].as_u64();
-                let message_id = last_message_id.unwrap();
-                let thread_id = message["thread_id"].as_u64().unwrap();
-
-                if exclude.contains(&message_id) || self.has_likes(&message, user_id) {
-                    info!(
-                        "Skipping message '{}' and aborting thread '{}'",
-                        message_id, thread_id
-                    );
-                    continue;
-                }
+            // Threads are processed concurrently but their results are written
+            // in the order the messages were listed (newest to oldest).
+            let batch: Vec<ApiMessage> = messages
+                .drain(..)
+                .filter(|e| queued.insert(e.thread_id()))
+                .collect();
+            let mut tasks = stream::iter(batch)
+                .map(|message| {
+                    let shared = &shared;
+                    async move {
+                        let thread_id = message.thread_id();
+
+                        if shared.lock().await.is_finished(thread_id) {
+                            return ThreadTask::Finished(message);
+                        }
+
+                        if exclude.contains(&message.id) {
+                            return ThreadTask::Skipped(message, "excluded");
+                        }
 
-                let group_id = message["group_id"].as_u64().unwrap();
+                        if self.has_likes(&message, user_id) {
+                            return ThreadTask::Skipped(message, "has likes");
+                        }
 
-                if !groups.contains_key(&group_id) {
-                    let muid = message["sender_id"].as_u64().unwrap();
+                        let result = self.delete_thread(token, thread_id, user_id, shared).await;
+                        ThreadTask::Done(message, result)
+                    }
+                })
+                .buffered(concurrency);
 
-                    if uid != muid {
-                        self.get_user_groups(&mut groups, token, muid).await?;
+            while let Some(task) = tasks.next().await {
+                match task {
+                    ThreadTask::Finished(message) => {
+                        info!("Thread '{}' is already finished", message.thread_id());
                     }
-                }
+                    ThreadTask::Skipped(message, reason) => {
+                        let message_id = message.id;
+                        let thread_id = message.thread_id();
+                        info!(
+                            "Skipping message '{}' and aborting thread '{}'",
+                            message_id, thread_id
+                        );
+                        let mut guard = shared.lock().await;
+                        let ctx = &mut **guard;
+
+                        if let Some(plan) = ctx.plan.as_mut() {
+                            let message = YammerMessage::from_api(&message, None, Some(&groups));
+                            add_plan_entry(&ctx.output, plan, PlanAction::Keep, reason, message)?;
+                        }
+
+                        if let Some(journal) = ctx.journal.as_mut() {
+                            journal.skipped(message_id, thread_id, reason)?;
+                            journal.finish_thread(thread_id)?;
+                            journal.cursor(message_id)?;
+                        }
+                    }
+                    ThreadTask::Done(message, result) => {
+                        let message_id = message.id;
+                        let thread_id = message.thread_id();
+                        let group_id = message.group_id.unwrap_or(0);
 
-                count += self
-                    .delete_thread(token, thread_id, user_id, &mut groups)
-                    .await?;
+                        if !groups.contains_key(&group_id) {
+                            let muid = message.sender_id.unwrap_or(0);
+
+                            if uid != muid {
+                                self.get_user_groups(&mut groups, token, muid).await?;
+                            }
+                        }
+
+                        let mut guard = shared.lock().await;
+                        let ctx = &mut **guard;
+
+                        // The thread might have been deleted since the messages were listed
+                        match result {
+                            Ok(result) => {
+                                count += result.count;
+                                write_thread_result(ctx, &groups, result)?;
+                            }
+                            Err(e) if is_not_found(e.as_ref()) => {
+                                warn!("Thread '{}' is not found", thread_id);
+                            }
+                            Err(e) => return Err(e),
+                        }
+
+                        if let Some(journal) = ctx.journal.as_mut() {
+                            journal.finish_thread(thread_id)?;
+                            journal.cursor(message_id)?;
+                        }
+                    }
+                }
             }
         }
 
-        return Ok(count);
+        drop(shared);
+        return YammerError::partial(count, ctx.failed);
     }
 
-    pub async fn delete_thread(
+    /// Deletes the user's messages of a thread from the newest to the oldest
+    /// and stops at the first message that cannot be deleted. The deleted (or
+    /// planned) messages are returned instead of written so `delete` can
+    /// write them in order while other threads are still being processed.
+    async fn delete_thread(
         &self,
         token: &str,
         thread_id: u64,
         user_id: Option<u64>,
-        groups: &mut HashMap<u64, YammerGroup>,
-    ) -> Result<u64> {
+        ctx: &Mutex<&mut DeleteContext>,
+    ) -> Result<ThreadResult> {
         // rate limit already taken in get_messages_in_thread
         info!("Fetching messages for thread {} for deletion", thread_id);
         let mut messages = VecDeque::new();
         // We will get ALL messages in the thread, not just the user's messages because we have to skip threads with likes and qothers' messages
         self.get_messages_in_thread(&mut messages, token, thread_id, None)
             .await?;
+        let mut result = ThreadResult::default();
 
         if messages.is_empty() {
-            return Ok(0);
+            return Ok(result);
         }
 
-        let mut count = 0u64;
+        let (plan, archive) = {
+            let ctx = ctx.lock().await;
+            (ctx.plan.is_some(), ctx.archive.is_some())
+        };
+        // Keep a copy of the whole thread to archive every message along with its replies
+        let thread: Vec<ApiMessage> = if archive {
+            messages.iter().cloned().collect()
+        } else {
+            Vec::new()
+        };
         info!("Deleting messages for thread {}", thread_id);
 
         // using pop_front to delete the messages in order (newest/child to oldest/parent)
         while let Some(message) = messages.pop_front() {
             // We will only delete the user's messages that has no interactions
-            if self.has_likes(&message, user_id)
-                || (user_id.is_some() && message["sender_id"].as_u64() != user_id)
-            {
+            let reason = if self.has_likes(&message, user_id) {
+                Some("has likes")
+            } else if user_id.is_some() && message.sender_id != user_id {
+                Some("sent by another user")
+            } else {
+                None
+            };
+
+            if let Some(reason) = reason {
+                let message_id = message.id;
                 info!(
                     "Skipping message '{}' and aborting thread '{}'",
-                    message["id"].as_u64().unwrap(),
-                    thread_id
+                    message_id, thread_id
                 );
-                break;
-            }
 
-            let message = YammerMessage::from_json(&message, None, Some(groups));
-            let url = format!("{}messages/{}.json", BASE_URL, &message.id);
-            let response = self
-                .send_with_rate_limit(
-                    self.client
-                        .delete(&url)
-                        .header("authorization", format!("Bearer {}", &token)),
-                    RLT_30,
-                )
-                .await?;
+                if plan {
+                    result
+                        .entries
+                        .push((PlanAction::Keep, reason.to_owned(), message));
+                    let reason = format!("thread aborted at message '{}'", message_id);
+
+                    while let Some(message) = messages.pop_front() {
+                        result
+                            .entries
+                            .push((PlanAction::Keep, reason.clone(), message));
+                    }
+                }
+
+                if let Some(journal) = ctx.lock().await.journal.as_mut() {
+                    journal.skipped(message_id, thread_id, reason)?;
+                }
+
+                break;
+            }
+
+            if let Some(archive) = ctx.lock().await.archive.as_mut() {
+                let replies = thread
+                    .iter()
+                    .filter(|e| e.replied_to_id == Some(message.id))
+                    .collect();
+
+                if let Err(e) = archive.write(thread_id, &message, replies) {
+                    error!(
+                        "Could not archive message '{}', refusing to delete it: {}",
+                        message.id,
+                        e.get_message()
+                    );
+                    return Err(e);
+                }
+            }
+
+            if plan {
+                result
+                    .entries
+                    .push((PlanAction::Delete, "no interactions".to_owned(), message));
+                result.count += 1;
+                continue;
+            }
+
+            if !self.delete_message(token, message.id).await? {
+                info!(
+                    "Skipping message '{}' and aborting thread '{}'",
+                    &message.id, thread_id
+                );
+                let mut ctx = ctx.lock().await;
+
+                if let Some(journal) = ctx.journal.as_mut() {
+                    journal.skipped(message.id, thread_id, "delete failed")?;
+                }
+
+                ctx.failed += 1;
 
-            if !response.status().is_success() {
-                error!(
-                    "Error deleting message '{}': {}",
-                    &message.id,
-                    response.text().await?
-                );
-                info!(
-                    "Skipping message '{}' and aborting thread '{}'",
-                    &message.id, thread_id
-                );
                 break;
             }
             info!("Deleted message '{}'", &message.id);
-            output::print_message(&message);
+
+            if let Some(journal) = ctx.lock().await.journal.as_mut() {
+                journal.deleted(message.id, thread_id)?;
+            }
+
+            result
+                .entries
+                .push((PlanAction::Delete, "deleted".to_owned(), message));
+            result.count += 1;
+        }
+
+        return Ok(result);
+    }
+
+    pub async fn apply_plan(
+        &self,
+        token: &str,
+        plan: &DeletePlan,
+        mut archive: Option<&mut Archive>,
+        output: &OutputWriter,
+    ) -> Result<u64> {
+        info!(
+            "Applying deletion plan with {} messages",
+            plan.messages.len()
+        );
+        let mut aborted_threads = HashSet::new();
+        // Whole threads fetched for the archive, so replies are kept as delete_thread does
+        let mut threads: HashMap<u64, Vec<ApiMessage>> = HashMap::new();
+        let mut count = 0u64;
+        let mut failed = 0u64;
+
+        for message in &plan.messages {
+            if aborted_threads.contains(&message.thread_id) {
+                info!(
+                    "Skipping message '{}' in aborted thread '{}'",
+                    message.id, message.thread_id
+                );
+                continue;
+            }
+
+            // Re-check the message because it might have changed since the plan was made
+            let current = match self.get_message(token, message.id).await? {
+                Some(it) => it,
+                None => {
+                    info!("Message '{}' no longer exists", message.id);
+                    continue;
+                }
+            };
+
+            if self.has_likes(&current, plan.user_id)
+                || current.sender_id != Some(message.sender_id)
+                || (plan.user_id.is_some() && current.sender_id != plan.user_id)
+            {
+                info!(
+                    "Skipping message '{}' and aborting thread '{}'",
+                    message.id, message.thread_id
+                );
+                aborted_threads.insert(message.thread_id);
+                continue;
+            }
+
+            if let Some(archive) = archive.as_deref_mut() {
+                if let Entry::Vacant(e) = threads.entry(message.thread_id) {
+                    let mut thread = Vec::new();
+                    self.get_messages_in_thread(&mut thread, token, message.thread_id, None)
+                        .await?;
+                    e.insert(thread);
+                }
+
+                let replies = threads[&message.thread_id]
+                    .iter()
+                    .filter(|e| e.replied_to_id == Some(message.id))
+                    .collect();
+
+                if let Err(e) = archive.write(message.thread_id, &current, replies) {
+                    error!(
+                        "Could not archive message '{}', refusing to delete it: {}",
+                        message.id,
+                        e.get_message()
+                    );
+                    return Err(e);
+                }
+            }
+
+            if !self.delete_message(token, message.id).await? {
+                info!(
+                    "Skipping message '{}' and aborting thread '{}'",
+                    message.id, message.thread_id
+                );
+                aborted_threads.insert(message.thread_id);
+                failed += 1;
+                continue;
+            }
+
+            info!("Deleted message '{}'", message.id);
+            output.write(message)?;
             count += 1;
         }
 
-        return Ok(count);
+        return YammerError::partial(count, failed);
     }
 
-    pub fn has_likes(&self, message: &Value, user_id: Option<u64>) -> bool {
-        let liked_by = message["liked_by"]["count"].as_u64().unwrap_or(0);
+    /// Replaces the body of the user's messages with the placeholder, in the
+    /// threads `delete` skips because of likes or other people's replies.
+    /// Threads `delete` can remove are left alone, as are other people's
+    /// messages. The messages are written as they were before they were
+    /// redacted.
+    pub async fn redact(
+        &self,
+        token: &str,
+        group_id: Option<u64>,
+        thread_id: Option<u64>,
+        user_id: u64,
+        ctx: &mut RedactContext,
+    ) -> Result<u64> {
+        let mut groups = HashMap::new();
+        self.get_user_groups(&mut groups, token, user_id).await?;
 
-        if liked_by == 0 {
-            return false;
+        if let Some(thread_id) = thread_id {
+            let count = self
+                .redact_thread(token, thread_id, user_id, &groups, ctx)
+                .await?;
+            return YammerError::partial(count, ctx.failed);
         }
 
-        if user_id.is_none() {
-            return true;
+        let mut messages = VecDeque::new();
+        let mut has_more = true;
+        let mut last_message_id = None;
+        let mut queued = HashSet::new();
+        let mut count = 0u64;
+
+        while has_more {
+            has_more = self
+                .get_messages(
+                    &mut messages,
+                    token,
+                    group_id,
+                    Some(user_id),
+                    last_message_id,
+                )
+                .await?;
+            last_message_id = messages.back().map(|e| e.id).or(last_message_id);
+
+            while let Some(message) = messages.pop_front() {
+                let thread_id = message.thread_id();
+
+                if !queued.insert(thread_id) {
+                    continue;
+                }
+
+                match self
+                    .redact_thread(token, thread_id, user_id, &groups, ctx)
+                    .await
+                {
+                    Ok(it) => count += it,
+                    Err(e) if is_not_found(e.as_ref()) => {
+                        warn!("Thread '{}' is not found", thread_id);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
         }
 
-        let liked_by = message["liked_by"]["names"]
-            .as_array()
-            .unwrap()
+        return YammerError::partial(count, ctx.failed);
+    }
+
+    async fn redact_thread(
+        &self,
+        token: &str,
+        thread_id: u64,
+        user_id: u64,
+        groups: &HashMap<u64, YammerGroup>,
+        ctx: &mut RedactContext,
+    ) -> Result<u64> {
+        info!("Fetching messages for thread {} for redaction", thread_id);
+        let mut messages = VecDeque::new();
+        // All the messages, to tell whether delete would skip the thread
+        self.get_messages_in_thread(&mut messages, token, thread_id, None)
+            .await?;
+
+        // The same checks delete makes before it aborts a thread
+        if !messages
             .iter()
-            .any(|e| e["user_id"].as_u64() != user_id);
-        return liked_by;
+            .any(|e| self.has_likes(e, Some(user_id)) || e.sender_id != Some(user_id))
+        {
+            info!(
+                "Thread '{}' has no likes or replies from others, leaving it to delete",
+                thread_id
+            );
+            return Ok(0);
+        }
+
+        let mut count = 0u64;
+
+        for message in messages
+            .into_iter()
+            .filter(|e| e.sender_id == Some(user_id))
+        {
+            let body = message.body.as_ref().map(|e| e.text()).unwrap_or("");
+
+            if body.trim() == ctx.placeholder.trim() {
+                info!("Message '{}' is already redacted", message.id);
+                continue;
+            }
+
+            if !ctx.dry_run {
+                if !self
+                    .update_message(token, message.id, &ctx.placeholder)
+                    .await?
+                {
+                    ctx.failed += 1;
+                    continue;
+                }
+
+                info!("Redacted message '{}'", message.id);
+            }
+
+            ctx.output
+                .write(&YammerMessage::from_api(&message, None, Some(groups)))?;
+            count += 1;
+        }
+
+        return Ok(count);
+    }
+
+    pub async fn get_message(&self, token: &str, message_id: u64) -> Result<Option<ApiMessage>> {
+        info!("Fetching message '{}'", message_id);
+        let url = format!("{}messages/{}.json", self.base_url, message_id);
+        let response = self
+            .send_with_rate_limit(
+                self.client
+                    .get(&url)
+                    .header("authorization", format!("Bearer {}", &token)),
+            )
+            .await?;
+
+        if response.status() == 404 {
+            return Ok(None);
+        }
+
+        let text = self.get_json_text(response).await?;
+        if text.is_empty() {
+            return Ok(None);
+        }
+        match serde_json::from_str::<ApiMessage>(&text) {
+            Ok(it) => Ok(Some(it)),
+            Err(e) => {
+                error!("{}\n{}", e, text);
+                Err(YammerError::Parse { body: text }.into())
+            }
+        }
     }
 
-    async fn send_with_rate_limit(
+    /// Creates the message and returns it as Yammer stored it.
+    pub async fn post_message(
         &self,
-        request: RequestBuilder,
-        rate_limit: u64,
-    ) -> Result<Response> {
-        loop {
-            let mut bkt = self.bucket.lock().await;
+        token: &str,
+        message: &ApiNewMessage,
+    ) -> Result<YammerMessage> {
+        if let Some(group_id) = message.group_id {
+            info!("Posting a message to group '{}'", group_id);
+        } else if let Some(replied_to_id) = message.replied_to_id {
+            info!("Posting a reply to message '{}'", replied_to_id);
+        } else {
+            info!(
+                "Posting a private message to {:?}",
+                message.direct_to_user_ids
+            );
+        }
 
-            if bkt.take() {
-                break;
+        let url = format!("{}messages.json", self.base_url);
+        let response = self
+            .send_with_rate_limit(
+                self.client
+                    .post(&url)
+                    .header("authorization", format!("Bearer {}", &token))
+                    .body(serde_json::to_string(message)?),
+            )
+            .await?;
+        let text = self.get_json_text(response).await?;
+        let json: ApiMessages = match serde_json::from_str(&text) {
+            Ok(it) => it,
+            Err(e) => {
+                error!("{}\n{}", e, text);
+                return Err(YammerError::Parse { body: text }.into());
+            }
+        };
+        let groups = referenced_groups(&json.references);
+
+        match json.messages.first() {
+            Some(message) => {
+                info!("Posted message '{}'", message.id);
+                return Ok(YammerMessage::from_api(message, None, Some(&groups)));
+            }
+            None => return Err(YammerError::Parse { body: text }.into()),
+        }
+    }
+
+    pub async fn delete_message(&self, token: &str, message_id: u64) -> Result<bool> {
+        let url = format!("{}messages/{}.json", self.base_url, message_id);
+        let response = self
+            .send_with_rate_limit(
+                self.client
+                    .delete(&url)
+                    .header("authorization", format!("Bearer {}", &token)),
+            )
+            .await?;
+
+        match response.status().as_u16() {
+            404 => {
+                warn!("Message '{}' is already deleted", message_id);
+                return Ok(true);
             }
+            401 => return Err(YammerError::Unauthorized.into()),
+            _ => {}
+        }
 
-            sleep(Duration::from_secs(1)).await;
+        if !response.status().is_success() {
+            error!(
+                "Error deleting message '{}': {}",
+                message_id,
+                response.text().await.map_err(YammerError::Network)?
+            );
+            return Ok(false);
         }
 
-        let mut tries = 0;
-        let response = loop {
+        Ok(true)
+    }
+
+    /// Replaces the body of the message through the message update endpoint.
+    pub async fn update_message(&self, token: &str, message_id: u64, body: &str) -> Result<bool> {
+        let url = format!("{}messages/{}.json", self.base_url, message_id);
+        let payload = serde_json::json!({ "body": body });
+        let response = self
+            .send_with_rate_limit(
+                self.client
+                    .put(&url)
+                    .header("authorization", format!("Bearer {}", &token))
+                    .body(payload.to_string()),
+            )
+            .await?;
+
+        if response.status() == 401 {
+            return Err(YammerError::Unauthorized.into());
+        }
+
+        if !response.status().is_success() {
+            error!(
+                "Error updating message '{}': {}",
+                message_id,
+                response.text().await.map_err(YammerError::Network)?
+            );
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Likes the message as the token's user.
+    pub async fn like_message(&self, token: &str, message_id: u64) -> Result<()> {
+        let url = format!(
+            "{}messages/liked_by/current.json?message_id={}",
+            self.base_url, message_id
+        );
+        let response = self
+            .send_with_rate_limit(
+                self.client
+                    .post(&url)
+                    .header("authorization", format!("Bearer {}", &token)),
+            )
+            .await?;
+        self.get_json_text(response).await?;
+        info!("Liked message '{}'", message_id);
+        Ok(())
+    }
+
+    /// Removes the like of the token's user from the message.
+    pub async fn unlike_message(&self, token: &str, message_id: u64) -> Result<bool> {
+        let url = format!(
+            "{}messages/liked_by/current.json?message_id={}",
+            self.base_url, message_id
+        );
+        let response = self
+            .send_with_rate_limit(
+                self.client
+                    .delete(&url)
+                    .header("authorization", format!("Bearer {}", &token)),
+            )
+            .await?;
+
+        match response.status().as_u16() {
+            404 => {
+                warn!("Message '{}' is not found", message_id);
+                return Ok(true);
+            }
+            401 => return Err(YammerError::Unauthorized.into()),
+            _ => {}
+        }
+
+        if !response.status().is_success() {
+            error!(
+                "Error unliking message '{}': {}",
+                message_id,
+                response.text().await.map_err(YammerError::Network)?
+            );
+            return Ok(false);
+        }
+
+        info!("Unliked message '{}'", message_id);
+        Ok(true)
+    }
+
+    /// Removes every like of the token's user, who must be `user_id`. The
+    /// unliked (or, on a dry run, liked) messages are written to `output`.
+    pub async fn unlike_all(
+        &self,
+        token: &str,
+        user_id: u64,
+        dry_run: bool,
+        output: &OutputWriter,
+    ) -> Result<u64> {
+        let mut messages = VecDeque::new();
+        let mut has_more = true;
+        let mut last_message_id = None;
+        let mut count = 0u64;
+        let mut failed = 0u64;
+
+        while has_more {
+            has_more = self
+                .get_liked_messages(&mut messages, token, user_id, last_message_id)
+                .await?;
+            last_message_id = messages.back().map(|e| e.id).or(last_message_id);
+
+            while let Some(message) = messages.pop_front() {
+                if !dry_run && !self.unlike_message(token, message.id).await? {
+                    failed += 1;
+                    continue;
+                }
+
+                output.write(&YammerMessage::from_api(&message, None, None))?;
+                count += 1;
+            }
+        }
+
+        return YammerError::partial(count, failed);
+    }
+
+    /// Fetches one page of every result type of a search.
+    pub async fn search_page(
+        &self,
+        token: &str,
+        query: &str,
+        page: u32,
+        num_per_page: u32,
+    ) -> Result<ApiSearch> {
+        info!(
+            "Searching for '{}' on page {} and num_per_page {}",
+            query, page, num_per_page
+        );
+        let url = format!(
+            "{}search.json?search={}&page={}&num_per_page={}",
+            self.base_url,
+            urlencoding::encode(query),
+            page,
+            num_per_page
+        );
+        let json: ApiSearch = self.get_json(token, &url).await?.unwrap_or_default();
+        return Ok(json);
+    }
+
+    /// Pages through every result type of a search and writes the messages,
+    /// users, groups and topics found, one type after the other.
+    pub async fn search(&self, token: &str, query: &str, output: &OutputWriter) -> Result<u64> {
+        let mut messages = Vec::new();
+        let mut users = Vec::new();
+        let mut groups = Vec::new();
+        let mut topics = Vec::new();
+        let mut senders = HashMap::new();
+        let mut message_groups = HashMap::new();
+        let (mut more_messages, mut more_users, mut more_groups, mut more_topics) =
+            (true, true, true, true);
+        let mut page = 1;
+
+        while more_messages || more_users || more_groups || more_topics {
+            let json = self.search_page(token, query, page, 20).await?;
+
+            if more_messages {
+                senders.extend(referenced_users(&json.messages.references));
+                message_groups.extend(referenced_groups(&json.messages.references));
+                more_messages =
+                    add_search_page(&mut messages, json.messages.messages, json.count.messages);
+            }
+
+            if more_users {
+                more_users = add_search_page(&mut users, json.users, json.count.users);
+            }
+
+            if more_groups {
+                more_groups = add_search_page(&mut groups, json.groups, json.count.groups);
+            }
+
+            if more_topics {
+                more_topics = add_search_page(&mut topics, json.topics, json.count.topics);
+            }
+
+            page += 1;
+        }
+
+        for message in &messages {
+            let message = YammerMessage::from_api(message, Some(&senders), Some(&message_groups));
+            output.write(&message)?;
+        }
+
+        for user in &users {
+            output.write(&YammerUser::from_api(user))?;
+        }
+
+        for group in &groups {
+            output.write(&YammerGroup::from_api(group))?;
+        }
+
+        for topic in &topics {
+            output.write(&YammerTopic::from_api(topic))?;
+        }
+
+        info!(
+            "Found {} messages, {} users, {} groups and {} topics",
+            messages.len(),
+            users.len(),
+            groups.len(),
+            topics.len()
+        );
+        return Ok((messages.len() + users.len() + groups.len() + topics.len()) as u64);
+    }
+
+    /// Whether someone other than `user_id` likes the message. Yammer truncates
+    /// the list of likers, so a list that is empty or shorter than the count
+    /// is treated as liked by others.
+    pub fn has_likes(&self, message: &ApiMessage, user_id: Option<u64>) -> bool {
+        let liked_by = match &message.liked_by {
+            Some(it) if it.count > 0 => it,
+            _ => return false,
+        };
+
+        if user_id.is_none() || liked_by.count > liked_by.names.len() as u64 {
+            return true;
+        }
+
+        return liked_by.names.iter().any(|e| e.user_id != user_id);
+    }
+
+    /// Sends the request once a token of its endpoint class is available, retrying transient
+    /// failures as allowed by the retry policy. Every retry takes a new token.
+    async fn send_with_rate_limit(&self, request: RequestBuilder) -> Result<Response> {
+        let mut request = request.build().map_err(YammerError::Network)?;
+
+        // Only the requests that carry a bearer get the current token
+        let auth = self
+            .auth
+            .as_ref()
+            .filter(|_| request.headers().contains_key(header::AUTHORIZATION));
+
+        if let Some(auth) = auth {
+            set_bearer(&mut request, &auth.access_token().await?)?;
+        }
+
+        let idempotent = RetryPolicy::is_retryable_method(request.method());
+        let class = EndpointClass::of(request.method(), request.url());
+        let mut attempt = 0;
+        let mut refreshed = false;
+        let mode = self.fixtures.as_ref().map(|e| e.mode());
+
+        loop {
+            // A replay does not reach the API, so it is not rate limited
+            if mode != Some(FixtureMode::Replay) {
+                self.limiter.acquire(class).await;
+            }
+
             let req = request.try_clone().expect("Failed to clone request");
-            match req.send().await {
+            let exhausted = attempt >= self.retry.max_retries;
+            self.stats.request();
+            let result = match (&self.fixtures, mode) {
+                (Some(fixtures), Some(FixtureMode::Replay)) => Ok(fixtures.load(&req)?),
+                _ => self.client.execute(req).await,
+            };
+
+            match result {
                 Ok(it) => {
-                    if it.status() == 429 {
-                        if tries > 3 {
-                            return Err(RateLimitTimeoutExceededError.into());
+                    let it = match (&self.fixtures, mode) {
+                        (Some(fixtures), Some(FixtureMode::Record)) => {
+                            fixtures.save(&request, it).await?
                         }
-                        warn!("Rate limit exceeded. Waiting for {} seconds", rate_limit);
-                        sleep(Duration::from_secs(rate_limit)).await;
-                        tries += 1;
+                        _ => it,
+                    };
+                    let status = it.status().as_u16();
+
+                    if let (401, false, Some(auth)) = (status, refreshed, auth) {
+                        let stale = bearer(&request).unwrap_or_default();
+                        let token = auth.refresh(&stale).await?;
+                        set_bearer(&mut request, &token)?;
+                        refreshed = true;
+                        info!("Replaying '{}' with the refreshed token", request.url());
                         continue;
+                    }
+
+                    if status == 429 {
+                        self.limiter.rate_limited(class).await;
+                    } else if !(idempotent && RetryPolicy::is_retryable_status(status)) {
+                        self.limiter.succeeded(class).await;
+                        return Ok(it);
+                    }
+
+                    let retry_after = retry_after(&it);
+
+                    if exhausted {
+                        self.stats.exhausted();
+
+                        if status == 429 {
+                            let retry_after = retry_after.map(|e| e.as_secs());
+                            return Err(YammerError::RateLimited { retry_after }.into());
+                        }
+
+                        return Ok(it);
+                    }
+
+                    let delay = self.retry.delay(attempt, retry_after);
+
+                    if status == 429 {
+                        warn!(
+                            "Rate limit exceeded. Retrying in {}",
+                            format_duration(delay)
+                        );
+                        self.stats.rate_limited(delay);
                     } else {
-                        break it;
+                        warn!(
+                            "'{}' responded with {}. Retrying in {}",
+                            request.url(),
+                            status,
+                            format_duration(delay)
+                        );
+                        self.stats.server_error(delay);
                     }
+
+                    sleep(delay).await;
+                }
+                Err(e) => {
+                    if !idempotent || !(e.is_timeout() || e.is_connect()) {
+                        return Err(YammerError::Network(e).into());
+                    }
+
+                    if exhausted {
+                        self.stats.exhausted();
+                        return Err(YammerError::Network(e).into());
+                    }
+
+                    let delay = self.retry.delay(attempt, None);
+                    warn!("{}. Retrying in {}", e, format_duration(delay));
+                    self.stats.network_error(delay);
+                    sleep(delay).await;
                 }
-                Err(e) => return Err(e.into()),
             }
-        };
-        Ok(response)
+
+            attempt += 1;
+        }
     }
 
-    async fn get_json_text(&self, response: Response) -> Result<String> {
+    async fn get_json<T: DeserializeOwned>(&self, token: &str, url: &str) -> Result<Option<T>> {
+        let response = self
+            .send_with_rate_limit(
+                self.client
+                    .get(url)
+                    .header("authorization", format!("Bearer {}", &token)),
+            )
+            .await?;
+        let text = self.get_json_text(response).await?;
+        if text.is_empty() {
+            return Ok(None);
+        }
+        match serde_json::from_str::<T>(&text) {
+            Ok(it) => Ok(Some(it)),
+            Err(e) => {
+                error!("{}\n{}", e, text);
+                Err(YammerError::Parse { body: text }.into())
+            }
+        }
+    }
+
+    async fn error_for_status(&self, response: Response) -> Result<Response> {
         if !response.status().is_success() {
-            return Err(response.error_for_status().unwrap_err().into());
+            let status = response.status().as_u16();
+            let retry_after = retry_after(&response).map(|e| e.as_secs());
+            let body = response.text().await.unwrap_or_default();
+            return Err(YammerError::from_status(status, body, retry_after).into());
         }
 
-        let text = response.text().await?;
+        Ok(response)
+    }
+
+    async fn get_json_text(&self, response: Response) -> Result<String> {
+        let response = self.error_for_status(response).await?;
+        let text = response.text().await.map_err(YammerError::Network)?;
 
         if text.is_empty()
             || text.len() == 2
@@ -559,3 +1469,103 @@ impl Service {
         Ok(text)
     }
 }
+
+fn bearer(request: &Request) -> Option<String> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|e| e.to_str().ok())
+        .and_then(|e| e.strip_prefix("Bearer "))
+        .map(|e| e.to_owned())
+}
+
+fn set_bearer(request: &mut Request, token: &str) -> Result<()> {
+    let value = HeaderValue::from_str(&format!("Bearer {}", token))?;
+    request.headers_mut().insert(header::AUTHORIZATION, value);
+    Ok(())
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|e| e.to_str().ok())
+        .and_then(parse_retry_after)
+}
+
+/// Adds a page of one search result type and tells whether more remain.
+fn add_search_page<T>(items: &mut Vec<T>, page: Vec<T>, total: u64) -> bool {
+    if page.is_empty() {
+        return false;
+    }
+
+    items.extend(page);
+    (items.len() as u64) < total
+}
+
+fn referenced_groups(references: &[ApiReference]) -> HashMap<u64, YammerGroup> {
+    references
+        .iter()
+        .filter(|e| e.kind.as_deref() == Some("group"))
+        .filter_map(|e| {
+            let id = e.id?;
+            let name = e.name.clone().unwrap_or_default();
+            let group = YammerGroup {
+                id,
+                display_name: e.full_name.clone().unwrap_or(name.clone()),
+                name,
+            };
+            Some((id, group))
+        })
+        .collect()
+}
+
+/// The referenced users that carry an email, which names the sender of a
+/// message.
+fn referenced_users(references: &[ApiReference]) -> HashMap<u64, YammerUser> {
+    references
+        .iter()
+        .filter(|e| e.kind.as_deref() == Some("user"))
+        .filter_map(|e| {
+            let id = e.id?;
+            let email = e.extra.get("email")?.as_str()?.to_owned();
+            let user = YammerUser {
+                id,
+                name: e.full_name.clone().or(e.name.clone()).unwrap_or_default(),
+                email,
+                ..Default::default()
+            };
+            Some((id, user))
+        })
+        .collect()
+}
+
+fn write_thread_result(
+    ctx: &mut DeleteContext,
+    groups: &HashMap<u64, YammerGroup>,
+    result: ThreadResult,
+) -> Result<()> {
+    for (action, reason, message) in result.entries {
+        let message = YammerMessage::from_api(&message, None, Some(groups));
+
+        match ctx.plan.as_mut() {
+            Some(plan) => add_plan_entry(&ctx.output, plan, action, &reason, message)?,
+            None => ctx.output.write(&message)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn add_plan_entry(
+    output: &OutputWriter,
+    plan: &mut Vec<PlanEntry>,
+    action: PlanAction,
+    reason: &str,
+    message: YammerMessage,
+) -> Result<()> {
+    let entry = PlanEntry::new(action, reason, message);
+    output.write(&entry)?;
+    plan.push(entry);
+    Ok(())
+}
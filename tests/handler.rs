@@ -0,0 +1,963 @@
+//! Runs `ActionHandler::process` end to end against the mock API.
+mod mock;
+
+use clap::Parser;
+use rustmix::{web::reqwest::Client, Result};
+use serde_json::Value;
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use yamutil::{
+    action_handler::ActionHandler,
+    common::{Args, YammerAction, YammerUser},
+    config::{self, Profile},
+    error,
+    fixture::Fixtures,
+    oauth::{OAuthClient, OAuthSettings, TokenRefresher},
+    output::{OutputFormat, OutputWriter},
+    rate_limit::{RateLimit, RateLimits},
+    retry::RetryPolicy,
+    service::Service,
+};
+
+use mock::{MockServer, REFRESH_TOKEN, TOKEN};
+
+const ME: &str = "me@example.com";
+
+/// An output sink the test can read back after the run.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Run {
+    result: Result<()>,
+    records: Vec<Value>,
+    service: Arc<Service>,
+}
+
+async fn run(server: &MockServer, token: &str, action: YammerAction) -> Run {
+    run_with(server.base_url(), token, action, None, None).await
+}
+
+async fn run_with(
+    base_url: &str,
+    token: &str,
+    action: YammerAction,
+    fixtures: Option<Fixtures>,
+    refresher: Option<TokenRefresher>,
+) -> Run {
+    let limit = RateLimit::new(1000, Duration::from_secs(1));
+    let limits = RateLimits {
+        read: limit,
+        write: limit,
+        messages: limit,
+        autocomplete: limit,
+        adaptive: true,
+    };
+    let retry = RetryPolicy::new(3, Duration::from_millis(1), Duration::from_millis(10));
+    let mut service = Service::new(base_url, retry, limits);
+
+    if let Some(fixtures) = fixtures {
+        service = service.with_fixtures(fixtures);
+    }
+
+    if let Some(refresher) = refresher {
+        service = service.with_token_refresher(refresher);
+    }
+
+    let service = Arc::new(service);
+    let buffer = Buffer::default();
+    let output = Arc::new(OutputWriter::with_sink(
+        OutputFormat::Ndjson,
+        Box::new(buffer.clone()),
+    ));
+    let handler = ActionHandler::new(
+        service.clone(),
+        output.clone(),
+        2,
+        OAuthSettings::default(),
+        None,
+    );
+    let result = handler.process(&token.to_owned(), &action).await;
+    output.finish().unwrap();
+    let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let records = text
+        .lines()
+        .filter(|e| !e.trim().is_empty())
+        .map(|e| serde_json::from_str(e).unwrap())
+        .collect();
+    Run {
+        result,
+        records,
+        service,
+    }
+}
+
+/// Users 1 (the token's owner), 2 and 3, and four threads started by user 1:
+/// 100 with a reply by user 1, 200 with a reply by user 2, 300 liked by
+/// user 2, and 400 with a reply liked by its own sender. Thread 500 belongs
+/// to user 2.
+async fn network() -> MockServer {
+    let server = MockServer::start().await;
+    server
+        .user(1, ME)
+        .user(2, "other@example.com")
+        .user(3, "third@example.com")
+        .group(10, "General", &[1, 2])
+        .group(20, "Random", &[1])
+        .message(100, 1, 10, None, &[])
+        .message(101, 1, 10, Some(100), &[])
+        .message(200, 1, 10, None, &[])
+        .message(201, 2, 10, Some(200), &[])
+        .message(300, 1, 20, None, &[2])
+        .message(400, 1, 20, None, &[])
+        .message(401, 1, 20, Some(400), &[1])
+        .message(500, 2, 10, None, &[]);
+    server
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("yamutil-test-{}-{}", std::process::id(), name))
+}
+
+/// An empty journal, so the delete runs do not write to the log directory.
+fn journal(name: &str) -> PathBuf {
+    let path = temp_path(&format!("{}.jsonl", name));
+    fs::write(&path, "").unwrap();
+    path
+}
+
+fn delete(email: Option<&str>, dry_run: bool, resume: Option<PathBuf>) -> YammerAction {
+    YammerAction::Delete {
+        group_id: None,
+        thread_id: None,
+        email: email.map(|e| e.to_owned()),
+        exclude: None,
+        dry_run,
+        plan_out: None,
+        resume,
+        archive: None,
+    }
+}
+
+fn ids(records: &[Value]) -> Vec<u64> {
+    let mut ids: Vec<u64> = records.iter().map(|e| e["id"].as_u64().unwrap()).collect();
+    ids.sort();
+    ids
+}
+
+#[tokio::test]
+async fn user_is_fetched_by_id() {
+    let server = network().await;
+    let run = run(&server, TOKEN, YammerAction::User { user_id: 2 }).await;
+
+    run.result.unwrap();
+    assert_eq!(run.records.len(), 1);
+    assert_eq!(run.records[0]["email"], "other@example.com");
+    assert_eq!(run.records[0]["name"], "User 2");
+}
+
+#[tokio::test]
+async fn missing_user_is_not_found() {
+    let server = network().await;
+    let run = run(&server, TOKEN, YammerAction::User { user_id: 9 }).await;
+
+    let e = run.result.unwrap_err();
+    assert!(error::is_not_found(e.as_ref()));
+    assert_eq!(error::exit_code(e.as_ref()), 4);
+}
+
+#[tokio::test]
+async fn invalid_token_is_unauthorized() {
+    let server = network().await;
+    let run = run(&server, "wrong", YammerAction::User { user_id: 1 }).await;
+
+    let e = run.result.unwrap_err();
+    assert_eq!(error::exit_code(e.as_ref()), 2);
+}
+
+#[tokio::test]
+async fn users_are_listed_until_an_empty_page() {
+    let server = network().await;
+    let run = run(&server, TOKEN, YammerAction::Users { group_id: None }).await;
+
+    run.result.unwrap();
+    assert_eq!(ids(&run.records), vec![1, 2, 3]);
+    assert!(server
+        .requests()
+        .contains(&"GET /api/v1/users.json?page=2&num_per_page=20".to_owned()));
+}
+
+#[tokio::test]
+async fn list_follows_older_available() {
+    let server = network().await;
+    let action = YammerAction::List {
+        group_id: None,
+        thread_id: None,
+        email: Some(ME.to_owned()),
+        all: false,
+    };
+    let run = run(&server, TOKEN, action).await;
+
+    run.result.unwrap();
+    assert_eq!(ids(&run.records), vec![100, 200, 300, 400]);
+    assert!(run.records.iter().all(|e| e["sender_name"] == ME));
+    assert!(server
+        .requests()
+        .contains(&"GET /api/v1/messages/sent.json?threaded=true&older_than=300".to_owned()));
+}
+
+#[tokio::test]
+async fn list_all_nests_replies() {
+    let server = network().await;
+    let action = YammerAction::List {
+        group_id: None,
+        thread_id: None,
+        email: Some(ME.to_owned()),
+        all: true,
+    };
+    let run = run(&server, TOKEN, action).await;
+
+    run.result.unwrap();
+    assert_eq!(ids(&run.records), vec![100, 200, 300, 400]);
+    let thread = run.records.iter().find(|e| e["id"] == 100).unwrap();
+    assert_eq!(thread["replies"][0]["id"], 101);
+    assert_eq!(thread["group_name"], "General");
+    // The reply of user 2 is filtered out
+    let thread = run.records.iter().find(|e| e["id"] == 200).unwrap();
+    assert!(thread["replies"].is_null());
+}
+
+#[tokio::test]
+async fn delete_skips_threads_with_interactions() {
+    let server = network().await;
+    let journal = journal("delete");
+    let run = run(
+        &server,
+        TOKEN,
+        delete(Some(ME), false, Some(journal.clone())),
+    )
+    .await;
+
+    fs::remove_file(&journal).unwrap();
+    run.result.unwrap();
+    let mut deleted = server.deleted();
+    deleted.sort();
+    assert_eq!(deleted, vec![100, 101, 400, 401]);
+    assert_eq!(ids(&run.records), vec![100, 101, 400, 401]);
+    assert_eq!(server.messages(), vec![200, 201, 300, 500]);
+}
+
+/// The message and reply ids of every archive record, keyed by message id.
+fn archived(path: &PathBuf) -> Vec<(u64, Vec<u64>)> {
+    let mut records: Vec<(u64, Vec<u64>)> = fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let record: Value = serde_json::from_str(line).unwrap();
+            let replies = record["replies"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| e["id"].as_u64().unwrap())
+                .collect();
+            (record["message"]["id"].as_u64().unwrap(), replies)
+        })
+        .collect();
+    records.sort();
+    records
+}
+
+#[tokio::test]
+async fn apply_plan_archives_replies_like_delete() {
+    let planned = network().await;
+    let plan = temp_path("plan.json");
+    let action = YammerAction::Delete {
+        group_id: None,
+        thread_id: None,
+        email: Some(ME.to_owned()),
+        exclude: None,
+        dry_run: false,
+        plan_out: Some(plan.clone()),
+        resume: None,
+        archive: None,
+    };
+    run(&planned, TOKEN, action).await.result.unwrap();
+    let plan_archive = temp_path("plan-archive.jsonl");
+    let action = YammerAction::ApplyPlan {
+        plan: plan.clone(),
+        archive: Some(plan_archive.clone()),
+    };
+    let applied = run(&planned, TOKEN, action).await;
+
+    let deleted = network().await;
+    let journal = journal("archive");
+    let delete_archive = temp_path("delete-archive.jsonl");
+    let action = YammerAction::Delete {
+        group_id: None,
+        thread_id: None,
+        email: Some(ME.to_owned()),
+        exclude: None,
+        dry_run: false,
+        plan_out: None,
+        resume: Some(journal.clone()),
+        archive: Some(delete_archive.clone()),
+    };
+    let direct = run(&deleted, TOKEN, action).await;
+
+    let from_plan = archived(&plan_archive);
+    let from_delete = archived(&delete_archive);
+    for path in [&plan, &plan_archive, &journal, &delete_archive] {
+        fs::remove_file(path).unwrap();
+    }
+    applied.result.unwrap();
+    direct.result.unwrap();
+    assert_eq!(
+        from_plan,
+        vec![
+            (100, vec![101]),
+            (101, vec![]),
+            (400, vec![401]),
+            (401, vec![])
+        ]
+    );
+    assert_eq!(from_plan, from_delete);
+}
+
+#[tokio::test]
+async fn resume_refuses_other_filters() {
+    let server = network().await;
+    let journal = journal("filters");
+    server.protect(101);
+    let first = run(
+        &server,
+        TOKEN,
+        delete(Some(ME), false, Some(journal.clone())),
+    )
+    .await;
+    assert!(first.result.is_err());
+    let action = YammerAction::Delete {
+        group_id: Some(20),
+        thread_id: None,
+        email: Some(ME.to_owned()),
+        exclude: None,
+        dry_run: false,
+        plan_out: None,
+        resume: Some(journal.clone()),
+        archive: None,
+    };
+    let other = run(&server, TOKEN, action).await;
+    let same = run(
+        &server,
+        TOKEN,
+        delete(Some(ME), false, Some(journal.clone())),
+    )
+    .await;
+
+    fs::remove_file(&journal).unwrap();
+    let e = other.result.unwrap_err();
+    assert_eq!(error::exit_code(e.as_ref()), 10);
+    // The thread of the refused delete is finished in the journal
+    same.result.unwrap();
+    let mut deleted = server.deleted();
+    deleted.sort();
+    assert_eq!(deleted, vec![400, 401]);
+}
+
+#[tokio::test]
+async fn delete_dry_run_deletes_nothing() {
+    let server = network().await;
+    let run = run(&server, TOKEN, delete(Some(ME), true, None)).await;
+
+    run.result.unwrap();
+    assert!(server.deleted().is_empty());
+    let planned: Vec<Value> = run
+        .records
+        .iter()
+        .filter(|e| e["action"] == "delete")
+        .cloned()
+        .collect();
+    assert_eq!(ids(&planned), vec![100, 101, 400, 401]);
+    let kept: Vec<Value> = run
+        .records
+        .iter()
+        .filter(|e| e["action"] == "keep")
+        .cloned()
+        .collect();
+    assert_eq!(ids(&kept), vec![200, 201, 300]);
+}
+
+#[tokio::test]
+async fn delete_keeps_threads_with_truncated_likes() {
+    let server = network().await;
+    let journal = journal("truncated");
+    // Only the sender is listed, but two more users like the message
+    server.message(600, 1, 10, None, &[1]).like_count(600, 3);
+    let run = run(
+        &server,
+        TOKEN,
+        delete(Some(ME), false, Some(journal.clone())),
+    )
+    .await;
+
+    fs::remove_file(&journal).unwrap();
+    run.result.unwrap();
+    assert!(server.messages().contains(&600));
+    assert!(!server.deleted().contains(&600));
+}
+
+#[tokio::test]
+async fn delete_reports_refused_deletes() {
+    let server = network().await;
+    let journal = journal("refused");
+    server.protect(101);
+    let run = run(
+        &server,
+        TOKEN,
+        delete(Some(ME), false, Some(journal.clone())),
+    )
+    .await;
+
+    fs::remove_file(&journal).unwrap();
+    let e = run.result.unwrap_err();
+    assert_eq!(error::exit_code(e.as_ref()), 9);
+    let mut deleted = server.deleted();
+    deleted.sort();
+    assert_eq!(deleted, vec![400, 401]);
+}
+
+#[tokio::test]
+async fn rate_limited_requests_are_retried() {
+    let server = network().await;
+    server.rate_limit(2);
+    let run = run(&server, TOKEN, YammerAction::User { user_id: 1 }).await;
+
+    run.result.unwrap();
+    assert_eq!(run.records[0]["email"], ME);
+    assert_eq!(server.requests().len(), 3);
+    assert!(run
+        .service
+        .retry_stats()
+        .to_string()
+        .contains("2 retries (2 rate limited"));
+}
+
+#[tokio::test]
+async fn replay_serves_the_recorded_responses() {
+    let server = network().await;
+    let dir = temp_path("fixtures");
+    let action = YammerAction::List {
+        group_id: None,
+        thread_id: None,
+        email: Some(ME.to_owned()),
+        all: true,
+    };
+    let recorded = run_with(
+        server.base_url(),
+        TOKEN,
+        action.clone(),
+        Some(Fixtures::record(&dir).unwrap()),
+        None,
+    )
+    .await;
+    recorded.result.unwrap();
+    let requests = server.requests().len();
+
+    // Nothing listens on the discard port, so every response comes from the fixtures
+    let replayed = run_with(
+        "http://127.0.0.1:9/api/v1/",
+        "",
+        action,
+        Some(Fixtures::replay(&dir).unwrap()),
+        None,
+    )
+    .await;
+    let files: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| fs::read_to_string(e.unwrap().path()).unwrap())
+        .collect();
+    fs::remove_dir_all(&dir).unwrap();
+
+    replayed.result.unwrap();
+    assert_eq!(replayed.records, recorded.records);
+    assert_eq!(files.len(), requests);
+    assert!(files.iter().all(|e| !e.contains(TOKEN)));
+}
+
+#[tokio::test]
+async fn replay_fails_on_unrecorded_requests() {
+    let dir = temp_path("empty-fixtures");
+    fs::create_dir_all(&dir).unwrap();
+    let run = run_with(
+        "http://127.0.0.1:9/api/v1/",
+        "",
+        YammerAction::User { user_id: 1 },
+        Some(Fixtures::replay(&dir).unwrap()),
+        None,
+    )
+    .await;
+    fs::remove_dir_all(&dir).unwrap();
+
+    let e = run.result.unwrap_err();
+    assert_eq!(error::exit_code(e.as_ref()), 11);
+}
+
+#[tokio::test]
+async fn post_creates_a_message_in_a_group() {
+    let server = network().await;
+    let action = YammerAction::Post {
+        group_id: Some(20),
+        replied_to_id: None,
+        to: Vec::new(),
+        body: Some("Deployed v1.2.3".to_owned()),
+        body_file: None,
+    };
+    let run = run(&server, TOKEN, action).await;
+
+    run.result.unwrap();
+    assert_eq!(run.records.len(), 1);
+    assert_eq!(run.records[0]["id"], 501);
+    assert_eq!(run.records[0]["body"], "Deployed v1.2.3");
+    assert_eq!(run.records[0]["group_name"], "Random");
+    assert!(server.messages().contains(&501));
+}
+
+#[tokio::test]
+async fn post_replies_in_the_thread() {
+    let server = network().await;
+    let body = temp_path("body.txt");
+    fs::write(&body, "Thanks!").unwrap();
+    let action = YammerAction::Post {
+        group_id: None,
+        replied_to_id: Some(201),
+        to: Vec::new(),
+        body: None,
+        body_file: Some(body.clone()),
+    };
+    let run = run(&server, TOKEN, action).await;
+    fs::remove_file(&body).unwrap();
+
+    run.result.unwrap();
+    assert_eq!(run.records[0]["thread_id"], 200);
+    assert_eq!(run.records[0]["replied_to_id"], 201);
+    assert_eq!(run.records[0]["body"], "Thanks!");
+}
+
+fn manifest(name: &str) -> PathBuf {
+    let path = temp_path(name);
+    fs::write(
+        &path,
+        "group_id,to,name,template\n\
+         20,,Ada,\"Welcome {name}, {{see}} the wiki\"\n\
+         ,other@example.com;3,Bob,Hi {name}\n\
+         10,,Eve,Hi {nickname}\n",
+    )
+    .unwrap();
+    path
+}
+
+#[tokio::test]
+async fn post_batch_reports_every_row() {
+    let server = network().await;
+    let path = manifest("manifest.csv");
+    let action = YammerAction::PostBatch {
+        manifest: path.clone(),
+        template: None,
+        dry_run: false,
+    };
+    let run = run(&server, TOKEN, action).await;
+    fs::remove_file(&path).unwrap();
+
+    let e = run.result.unwrap_err();
+    assert_eq!(error::exit_code(e.as_ref()), 9);
+    assert_eq!(run.records.len(), 3);
+    assert_eq!(run.records[0]["status"], "posted");
+    assert_eq!(run.records[0]["body"], "Welcome Ada, {see} the wiki");
+    assert_eq!(run.records[1]["status"], "posted");
+    assert_eq!(run.records[1]["to"], serde_json::json!([2, 3]));
+    assert_eq!(run.records[2]["status"], "failed");
+    let posted: Vec<u64> = run
+        .records
+        .iter()
+        .filter_map(|e| e["message_id"].as_u64())
+        .collect();
+    assert_eq!(posted, vec![501, 502]);
+    assert!(server.messages().contains(&502));
+}
+
+#[tokio::test]
+async fn post_batch_dry_run_posts_nothing() {
+    let server = network().await;
+    let path = manifest("dry-run-manifest.csv");
+    let action = YammerAction::PostBatch {
+        manifest: path.clone(),
+        template: None,
+        dry_run: true,
+    };
+    let run = run(&server, TOKEN, action).await;
+    fs::remove_file(&path).unwrap();
+
+    assert!(run.result.is_err());
+    assert_eq!(run.records[0]["status"], "planned");
+    assert_eq!(run.records[1]["status"], "planned");
+    assert_eq!(run.records[1]["to"], serde_json::json!([3]));
+    assert_eq!(
+        run.records[1]["unresolved"],
+        serde_json::json!(["other@example.com"])
+    );
+    assert!(!server.requests().iter().any(|e| e.starts_with("POST ")));
+    assert!(!server.requests().iter().any(|e| e.contains("by_email")));
+}
+
+fn redact(thread_id: Option<u64>, dry_run: bool) -> YammerAction {
+    YammerAction::Redact {
+        group_id: None,
+        thread_id,
+        email: ME.to_owned(),
+        placeholder: "[redacted]".to_owned(),
+        dry_run,
+    }
+}
+
+#[tokio::test]
+async fn redact_scrubs_the_threads_delete_skips() {
+    let server = network().await;
+    server.protect(300);
+    let run = run(&server, TOKEN, redact(None, false)).await;
+
+    let e = run.result.unwrap_err();
+    assert_eq!(error::exit_code(e.as_ref()), 9);
+    // Thread 200 has a reply of user 2 and thread 300 is liked by user 2
+    assert_eq!(ids(&run.records), vec![200]);
+    // The messages are reported with the bodies they had
+    assert!(run.records.iter().all(|e| e["body"] != "[redacted]"));
+    assert_eq!(server.body(200).as_deref(), Some("[redacted]"));
+
+    // Threads 100 and 400 are left to delete, and others' messages are not touched
+    for id in [100, 101, 201, 300, 400, 401] {
+        assert_eq!(
+            server.body(id).as_deref(),
+            Some(format!("Message {}", id).as_str())
+        );
+    }
+
+    assert!(server.deleted().is_empty());
+}
+
+#[tokio::test]
+async fn redact_dry_run_changes_nothing() {
+    let server = network().await;
+    let run = run(&server, TOKEN, redact(Some(200), true)).await;
+
+    run.result.unwrap();
+    assert_eq!(ids(&run.records), vec![200]);
+    assert_eq!(server.body(200).as_deref(), Some("Message 200"));
+    assert!(!server.requests().iter().any(|e| e.starts_with("PUT ")));
+}
+
+#[tokio::test]
+async fn like_and_unlike_a_message() {
+    let server = network().await;
+    let run_like = run(&server, TOKEN, YammerAction::Like { message_id: 500 }).await;
+    run_like.result.unwrap();
+    assert_eq!(server.likes(500), vec![1]);
+
+    let run_unlike = run(&server, TOKEN, YammerAction::Unlike { message_id: 500 }).await;
+    run_unlike.result.unwrap();
+    assert!(server.likes(500).is_empty());
+}
+
+#[tokio::test]
+async fn unlike_all_pages_through_the_liked_messages() {
+    let server = network().await;
+    server
+        .message(600, 2, 10, None, &[1])
+        .message(601, 2, 10, Some(600), &[1, 2])
+        .message(700, 2, 10, None, &[1]);
+    let action = YammerAction::UnlikeAll {
+        email: ME.to_owned(),
+        dry_run: false,
+    };
+    let run = run(&server, TOKEN, action).await;
+
+    run.result.unwrap();
+    // 401 is liked by its own sender, user 1
+    assert_eq!(ids(&run.records), vec![401, 600, 601, 700]);
+    assert_eq!(server.likes(601), vec![2]);
+    assert_eq!(server.likes(300), vec![2]);
+    assert!(server.likes(700).is_empty());
+}
+
+#[tokio::test]
+async fn unlike_all_refuses_other_users() {
+    let server = network().await;
+    let action = YammerAction::UnlikeAll {
+        email: "other@example.com".to_owned(),
+        dry_run: false,
+    };
+    let run = run(&server, TOKEN, action).await;
+
+    let e = run.result.unwrap_err();
+    assert_eq!(error::exit_code(e.as_ref()), 3);
+    assert_eq!(server.likes(300), vec![2]);
+}
+
+#[tokio::test]
+async fn search_pages_through_every_result_type() {
+    let server = network().await;
+    server.group(30, "Message board", &[1]);
+    let action = YammerAction::Search {
+        query: "message".to_owned(),
+    };
+    let run = run(&server, TOKEN, action).await;
+
+    run.result.unwrap();
+    assert_eq!(
+        ids(&run.records),
+        vec![30, 100, 101, 200, 201, 300, 400, 401, 500]
+    );
+    let reply = &run.records[3];
+    assert_eq!(reply["id"], 201);
+    assert_eq!(reply["sender_name"], "other@example.com");
+    assert_eq!(reply["group_name"], "General");
+    // The messages come first, then the groups
+    assert_eq!(run.records[8]["display_name"], "Message board");
+}
+
+#[tokio::test]
+async fn search_finds_users() {
+    let server = network().await;
+    let action = YammerAction::Search {
+        query: "example.com".to_owned(),
+    };
+    let run = run(&server, TOKEN, action).await;
+
+    run.result.unwrap();
+    assert_eq!(ids(&run.records), vec![1, 2, 3]);
+    assert_eq!(run.records[1]["email"], "other@example.com");
+}
+
+fn login(server: &MockServer, device: bool) -> YammerAction {
+    let settings = server.oauth();
+    YammerAction::Login {
+        device,
+        client_id: settings.client_id,
+        client_secret: None,
+        authorize_url: settings.authorize_url,
+        token_url: settings.token_url,
+        device_code_url: settings.device_code_url,
+        scope: None,
+        port: 0,
+    }
+}
+
+#[tokio::test]
+async fn authorization_code_is_exchanged_with_the_pkce_verifier() {
+    let server = network().await;
+    let client = OAuthClient::new(&server.oauth()).unwrap();
+    // The browser follows the redirect of the authorize endpoint to the listener
+    let response = client
+        .authorization_code_with(0, |url| {
+            let url = url.to_string();
+            tokio::spawn(async move { Client::new().get(url).send().await });
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(response.access_token, server.access_token());
+    assert_eq!(response.refresh_token.as_deref(), Some(REFRESH_TOKEN));
+    // The redirect names the address the listener is bound to
+    assert!(server
+        .redirect_uri()
+        .unwrap()
+        .starts_with("http://127.0.0.1:"));
+}
+
+#[tokio::test]
+async fn authorization_code_gives_up_without_a_redirect() {
+    let server = network().await;
+    let client = OAuthClient::new(&server.oauth())
+        .unwrap()
+        .with_login_timeout(Duration::from_millis(100));
+    // The browser is never opened
+    let e = client.authorization_code_with(0, |_| {}).await.unwrap_err();
+
+    assert_eq!(error::exit_code(e.as_ref()), 2);
+    assert!(e.to_string().contains("No sign in within 100ms"));
+}
+
+#[tokio::test]
+async fn login_polls_the_device_code_and_later_actions_reuse_the_token() {
+    let server = network().await;
+    let dir = temp_path("config");
+    // Only this test reads the config directory
+    std::env::set_var("XDG_CONFIG_HOME", &dir);
+    std::env::remove_var(config::TOKEN_ENV);
+    server.pending(1);
+    let run_login = run(&server, "", login(&server, true)).await;
+
+    run_login.result.unwrap();
+    assert_eq!(server.polls(), 2);
+    let path = dir.join("yamutil").join("tokens.json");
+    let stored: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(stored["access_token"], server.access_token());
+    assert_eq!(stored["refresh_token"], REFRESH_TOKEN);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let args = Args::parse_from(["yamutil", "user", "--user-id", "1"]);
+    let (token, refresher) = config::resolve_token(&args, &Profile::default(), None, None).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(token, server.access_token());
+    assert!(refresher.is_some());
+
+    let run_user = run(&server, &token, args.action).await;
+    run_user.result.unwrap();
+    assert_eq!(run_user.records[0]["email"], ME);
+}
+
+fn refresher(server: &MockServer) -> Option<TokenRefresher> {
+    Some(TokenRefresher::from_refresh_token(&server.oauth(), TOKEN, REFRESH_TOKEN).unwrap())
+}
+
+#[tokio::test]
+async fn delete_finishes_after_the_token_expires() {
+    let server = network().await;
+    let journal = journal("expired");
+    // The token expires when the two workers fetch the threads of the second page
+    server.expire_after(7);
+    let run = run_with(
+        server.base_url(),
+        TOKEN,
+        delete(Some(ME), false, Some(journal.clone())),
+        None,
+        refresher(&server),
+    )
+    .await;
+
+    fs::remove_file(&journal).unwrap();
+    run.result.unwrap();
+    // The workers that failed with the expired token share one refresh
+    assert_eq!(server.refreshes(), 1);
+    let mut deleted = server.deleted();
+    deleted.sort();
+    assert_eq!(deleted, vec![100, 101, 400, 401]);
+    // Every request refused with the expired token is replayed with the new one
+    let exchanges = server.exchanges();
+    let token = server.access_token();
+
+    for (i, (request, _, status)) in exchanges.iter().enumerate() {
+        if *status == 401 {
+            assert!(exchanges[i..]
+                .iter()
+                .any(|e| &e.0 == request && e.1 == token && e.2 < 300));
+        }
+    }
+
+    assert!(exchanges.iter().filter(|e| e.2 == 401).count() >= 2);
+}
+
+#[tokio::test]
+async fn a_second_401_after_refreshing_is_unauthorized() {
+    let server = network().await;
+    server.revoke();
+    let run = run_with(
+        server.base_url(),
+        TOKEN,
+        YammerAction::User { user_id: 1 },
+        None,
+        refresher(&server),
+    )
+    .await;
+
+    let e = run.result.unwrap_err();
+    assert_eq!(error::exit_code(e.as_ref()), 2);
+    assert_eq!(server.refreshes(), 1);
+    // The request is replayed once with the refreshed token, then given up
+    let exchanges = server.exchanges();
+    assert_eq!(exchanges.len(), 2);
+    assert_eq!(exchanges[0].1, TOKEN);
+    assert_eq!(exchanges[1].1, server.access_token());
+}
+
+#[tokio::test]
+async fn export_sends_the_token_to_the_api_host_only() {
+    let server = network().await;
+    let files = MockServer::start().await;
+    server
+        .attachment(100, 1, &server.file_url("own.txt"))
+        .attachment(100, 2, &files.file_url("other.txt"))
+        .attachment(101, 3, &files.file_url("missing"));
+    let dir = temp_path("export");
+    let action = YammerAction::Export {
+        dir: dir.clone(),
+        group_id: None,
+        email: Some(ME.to_owned()),
+        attachments: true,
+    };
+    let run = run(&server, TOKEN, action).await;
+    let own = dir.join("attachments").join("100").join("1-own.txt");
+    let other = dir.join("attachments").join("100").join("2-other.txt");
+    let saved = (own.is_file(), other.is_file());
+    fs::remove_dir_all(&dir).unwrap();
+
+    // The missing attachment does not stop the export
+    let e = run.result.unwrap_err();
+    assert_eq!(error::exit_code(e.as_ref()), 9);
+    assert_eq!(saved, (true, true));
+    let own = server
+        .exchanges()
+        .into_iter()
+        .find(|e| e.0.contains("/files/own.txt"))
+        .unwrap();
+    assert_eq!(own.1, TOKEN);
+    let other = files.exchanges();
+    assert_eq!(other.len(), 2);
+    assert!(other.iter().all(|e| e.1.is_empty()));
+}
+
+#[test]
+fn csv_quotes_the_cells_that_need_it() {
+    let buffer = Buffer::default();
+    let output = OutputWriter::with_sink(OutputFormat::Csv, Box::new(buffer.clone()));
+    let user = |id: u64, name: &str, job_title: &str| YammerUser {
+        id,
+        name: name.to_owned(),
+        email: format!("user{}@example.com", id),
+        network_id: 1,
+        state: "active".to_owned(),
+        job_title: job_title.to_owned(),
+    };
+    output
+        .write(&user(1, "Doe, \"Jo\"", "Line 1\nLine 2"))
+        .unwrap();
+    output.write(&user(2, "User 2", "")).unwrap();
+    output.finish().unwrap();
+
+    let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    assert_eq!(
+        text,
+        "id,name,email,network_id,state,job_title\n\
+         1,\"Doe, \"\"Jo\"\"\",user1@example.com,1,active,\"Line 1\nLine 2\"\n\
+         2,User 2,user2@example.com,1,active,\n"
+    );
+}
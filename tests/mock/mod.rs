@@ -0,0 +1,916 @@
+//! A fake Yammer REST API served on a local port. It keeps users, groups and
+//! messages in memory, pages threaded message lists with `older_available`,
+//! creates, updates, deletes, likes and unlikes messages, searches, and can
+//! answer the next requests with 429. Its `oauth2/` endpoints authorize with
+//! PKCE, hand out device codes and issue and refresh tokens, and the token can
+//! be made to expire partway through a run. Attachments are served from
+//! `files/` without authentication.
+#![allow(dead_code)]
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::sleep,
+};
+use url::form_urlencoded;
+use yamutil::{
+    api::{ApiAttachment, ApiBody, ApiGroup, ApiLikedBy, ApiLiker, ApiMessage, ApiUser},
+    oauth::OAuthSettings,
+};
+
+pub const TOKEN: &str = "mock-token";
+pub const CLIENT_ID: &str = "mock-client";
+pub const REFRESH_TOKEN: &str = "mock-refresh";
+const AUTHORIZATION_CODE: &str = "mock-code";
+const DEVICE_CODE: &str = "mock-device-code";
+/// How long the token endpoint takes to answer.
+const TOKEN_DELAY: Duration = Duration::from_millis(100);
+/// The number of threads in a page of messages.
+pub const PAGE_SIZE: usize = 2;
+
+#[derive(Debug, Default)]
+struct State {
+    users: Vec<ApiUser>,
+    groups: Vec<ApiGroup>,
+    /// The groups of every user.
+    members: HashMap<u64, Vec<u64>>,
+    messages: Vec<ApiMessage>,
+    /// The user the token belongs to, whose messages are listed by `messages/sent`.
+    current_user: u64,
+    /// The number of upcoming requests answered with 429.
+    rate_limited: usize,
+    /// Messages the API refuses to delete.
+    protected: Vec<u64>,
+    deleted: Vec<u64>,
+    requests: Vec<String>,
+    /// The access token the API accepts. Empty once it has expired.
+    access_token: String,
+    /// The number of API requests the token is accepted for before it expires.
+    expire_after: Option<usize>,
+    /// Rejects every token, even a refreshed one.
+    revoked: bool,
+    /// The request, bearer token and status of every API exchange.
+    exchanges: Vec<(String, String, u16)>,
+    oauth: OAuthState,
+}
+
+#[derive(Debug, Default)]
+struct OAuthState {
+    /// The PKCE challenge and redirect URI of the last authorize request.
+    challenge: Option<String>,
+    redirect_uri: Option<String>,
+    /// The number of device code polls answered with `authorization_pending`.
+    pending: usize,
+    polls: usize,
+    refreshes: usize,
+    issued: usize,
+}
+
+impl State {
+    fn add_message(
+        &mut self,
+        id: u64,
+        sender_id: u64,
+        group_id: Option<u64>,
+        replied_to_id: Option<u64>,
+        liked_by: &[u64],
+        body: String,
+    ) -> &ApiMessage {
+        let thread_id = match replied_to_id {
+            Some(parent) => self
+                .messages
+                .iter()
+                .find(|e| e.id == parent)
+                .map(|e| e.thread_id())
+                .expect("the parent message must be added first"),
+            None => id,
+        };
+        self.messages.push(ApiMessage {
+            id,
+            sender_id: Some(sender_id),
+            sender_type: Some("user".to_owned()),
+            replied_to_id,
+            network_id: Some(1),
+            group_id,
+            thread_id: Some(thread_id),
+            privacy: Some(
+                if group_id.is_some() {
+                    "public"
+                } else {
+                    "private"
+                }
+                .to_owned(),
+            ),
+            created_at: Some("2024/01/01 00:00:00 +0000".to_owned()),
+            body: Some(ApiBody {
+                plain: Some(body),
+                ..Default::default()
+            }),
+            liked_by: Some(ApiLikedBy {
+                count: liked_by.len() as u64,
+                names: liked_by
+                    .iter()
+                    .map(|e| ApiLiker {
+                        user_id: Some(*e),
+                        full_name: Some(format!("User {}", e)),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        self.messages.last().unwrap()
+    }
+
+    /// Creates a message from a `POST messages.json` body, like `ApiNewMessage`.
+    fn post(&mut self, body: &Value) -> Response {
+        let text = body["body"].as_str().unwrap_or_default().to_owned();
+        let replied_to_id = body["replied_to_id"].as_u64();
+        let parent_group = replied_to_id
+            .and_then(|id| self.messages.iter().find(|e| e.id == id))
+            .map(|e| e.group_id);
+        let group_id = match parent_group {
+            Some(it) => it,
+            None => body["group_id"].as_u64(),
+        };
+
+        if text.is_empty() || replied_to_id.is_some() && parent_group.is_none() {
+            return Response::status(400);
+        }
+
+        let id = self.messages.iter().map(|e| e.id).max().unwrap_or(0) + 1;
+        let sender_id = self.current_user;
+        let message = self
+            .add_message(id, sender_id, group_id, replied_to_id, &[], text)
+            .clone();
+        let references: Vec<Value> = self
+            .groups
+            .iter()
+            .filter(|e| Some(e.id) == group_id)
+            .map(|e| json!({ "type": "group", "id": e.id, "name": e.name, "full_name": e.full_name }))
+            .collect();
+        let mut response = Response::json(&json!({
+            "messages": [message],
+            "meta": {},
+            "references": references,
+        }));
+        response.status = 201;
+        response
+    }
+}
+
+pub struct MockServer {
+    base_url: String,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/api/v1/", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State {
+            access_token: TOKEN.to_owned(),
+            ..Default::default()
+        }));
+        let shared = state.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = shared.clone();
+                tokio::spawn(async move {
+                    let _ = serve(stream, state).await;
+                });
+            }
+        });
+        Self {
+            base_url,
+            state,
+            task,
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The client and endpoints of the fake OAuth server.
+    pub fn oauth(&self) -> OAuthSettings {
+        let root = self.base_url.trim_end_matches("api/v1/");
+        OAuthSettings {
+            client_id: Some(CLIENT_ID.to_owned()),
+            authorize_url: Some(format!("{}oauth2/authorize", root)),
+            token_url: Some(format!("{}oauth2/token", root)),
+            device_code_url: Some(format!("{}oauth2/devicecode", root)),
+            ..Default::default()
+        }
+    }
+
+    /// Answers the next `count` device code polls with `authorization_pending`.
+    pub fn pending(&self, count: usize) {
+        self.state.lock().unwrap().oauth.pending = count;
+    }
+
+    /// Accepts the current token for `count` more API requests only.
+    pub fn expire_after(&self, count: usize) {
+        self.state.lock().unwrap().expire_after = Some(count);
+    }
+
+    /// Answers every API request with 401, whatever the token.
+    pub fn revoke(&self) {
+        self.state.lock().unwrap().revoked = true;
+    }
+
+    /// The access token the API accepts now.
+    pub fn access_token(&self) -> String {
+        self.state.lock().unwrap().access_token.clone()
+    }
+
+    /// The number of device code polls of the token endpoint.
+    pub fn polls(&self) -> usize {
+        self.state.lock().unwrap().oauth.polls
+    }
+
+    /// The redirect URI of the last authorize request.
+    pub fn redirect_uri(&self) -> Option<String> {
+        self.state.lock().unwrap().oauth.redirect_uri.clone()
+    }
+
+    /// The request, bearer token and status of every API request, in order.
+    pub fn exchanges(&self) -> Vec<(String, String, u16)> {
+        self.state.lock().unwrap().exchanges.clone()
+    }
+
+    /// The number of tokens issued for a refresh token.
+    pub fn refreshes(&self) -> usize {
+        self.state.lock().unwrap().oauth.refreshes
+    }
+
+    /// Adds a user. The first user added owns the token.
+    pub fn user(&self, id: u64, email: &str) -> &Self {
+        let mut state = self.state.lock().unwrap();
+
+        if state.users.is_empty() {
+            state.current_user = id;
+        }
+
+        state.users.push(ApiUser {
+            id,
+            kind: Some("user".to_owned()),
+            name: Some(email.split('@').next().unwrap_or_default().to_owned()),
+            full_name: Some(format!("User {}", id)),
+            email: Some(email.to_owned()),
+            network_id: Some(1),
+            state: Some("active".to_owned()),
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Adds a group the given users belong to.
+    pub fn group(&self, id: u64, name: &str, members: &[u64]) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        state.groups.push(ApiGroup {
+            id,
+            kind: Some("group".to_owned()),
+            name: Some(name.to_owned()),
+            full_name: Some(name.to_owned()),
+            privacy: Some("public".to_owned()),
+            ..Default::default()
+        });
+
+        for member in members {
+            state.members.entry(*member).or_default().push(id);
+        }
+
+        self
+    }
+
+    /// Adds a message. A message without `replied_to_id` starts a thread
+    /// whose id is the message id.
+    pub fn message(
+        &self,
+        id: u64,
+        sender_id: u64,
+        group_id: u64,
+        replied_to_id: Option<u64>,
+        liked_by: &[u64],
+    ) -> &Self {
+        let body = format!("Message {}", id);
+        self.state.lock().unwrap().add_message(
+            id,
+            sender_id,
+            Some(group_id),
+            replied_to_id,
+            liked_by,
+            body,
+        );
+        self
+    }
+
+    /// Attaches a file to the message.
+    pub fn attachment(&self, message_id: u64, id: u64, download_url: &str) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        let message = state.messages.iter_mut().find(|e| e.id == message_id);
+
+        if let Some(message) = message {
+            message.attachments.push(ApiAttachment {
+                id: Some(id),
+                kind: Some("file".to_owned()),
+                name: download_url.rsplit('/').next().map(|e| e.to_owned()),
+                download_url: Some(download_url.to_owned()),
+                ..Default::default()
+            });
+        }
+
+        self
+    }
+
+    /// The URL of a file served by this server. Only `missing` is not found.
+    pub fn file_url(&self, name: &str) -> String {
+        format!(
+            "{}files/{}",
+            self.base_url.trim_end_matches("api/v1/"),
+            name
+        )
+    }
+
+    /// Sets the like count of the message without adding likers, like the
+    /// truncated list Yammer sends for popular messages.
+    pub fn like_count(&self, message_id: u64, count: u64) {
+        let mut state = self.state.lock().unwrap();
+        let message = state.messages.iter_mut().find(|e| e.id == message_id);
+
+        if let Some(liked_by) = message.and_then(|e| e.liked_by.as_mut()) {
+            liked_by.count = count;
+        }
+    }
+
+    /// Answers the next `count` requests with 429 and `Retry-After: 0`.
+    pub fn rate_limit(&self, count: usize) {
+        self.state.lock().unwrap().rate_limited = count;
+    }
+
+    /// Answers deletes and updates of the message with 403.
+    pub fn protect(&self, message_id: u64) {
+        self.state.lock().unwrap().protected.push(message_id);
+    }
+
+    /// The ids of the deleted messages, in the order they were deleted.
+    pub fn deleted(&self) -> Vec<u64> {
+        self.state.lock().unwrap().deleted.clone()
+    }
+
+    /// The ids of the messages that still exist.
+    pub fn messages(&self) -> Vec<u64> {
+        let state = self.state.lock().unwrap();
+        state.messages.iter().map(|e| e.id).collect()
+    }
+
+    /// The ids of the users who like the message.
+    pub fn likes(&self, message_id: u64) -> Vec<u64> {
+        let state = self.state.lock().unwrap();
+        state
+            .messages
+            .iter()
+            .find(|e| e.id == message_id)
+            .and_then(|e| e.liked_by.as_ref())
+            .map(|e| e.names.iter().filter_map(|e| e.user_id).collect())
+            .unwrap_or_default()
+    }
+
+    /// The plain body of the message.
+    pub fn body(&self, message_id: u64) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        let message = state.messages.iter().find(|e| e.id == message_id)?;
+        message.body.as_ref()?.plain.clone()
+    }
+
+    /// Every request received, as `<method> <path and query>`.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    fn json<T: Serialize>(value: &T) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body: serde_json::to_string(value).unwrap(),
+        }
+    }
+
+    fn status(status: u16) -> Self {
+        let body = match status {
+            200 => String::new(),
+            _ => json!({ "error": status }).to_string(),
+        };
+        Self {
+            status,
+            headers: Vec::new(),
+            body,
+        }
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let target = parts.next().unwrap_or_default().to_owned();
+    let mut authorization = None;
+    let mut length = 0usize;
+
+    loop {
+        let mut header = String::new();
+
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            match name.trim().to_lowercase().as_str() {
+                "authorization" => authorization = Some(value.trim().to_owned()),
+                "content-length" => length = value.trim().parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+    }
+
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await?;
+    let response = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(format!("{} {}", method, target));
+        let response = if target.starts_with("/oauth2/") {
+            oauth_route(&mut state, &method, &target, &body)
+        } else if let Some(name) = target.strip_prefix("/files/") {
+            match name {
+                "missing" => Response::status(404),
+                _ => Response {
+                    status: 200,
+                    headers: Vec::new(),
+                    body: format!("Contents of {}", name),
+                },
+            }
+        } else if state.rate_limited > 0 {
+            state.rate_limited -= 1;
+            let mut response = Response::status(429);
+            response.headers.push(("Retry-After", "0".to_owned()));
+            response
+        } else if state.revoked
+            || state.access_token.is_empty()
+            || authorization.as_deref() != Some(&format!("Bearer {}", state.access_token))
+        {
+            Response::status(401)
+        } else if state.expire_after == Some(0) {
+            state.access_token.clear();
+            state.expire_after = None;
+            Response::status(401)
+        } else {
+            if let Some(count) = state.expire_after.as_mut() {
+                *count -= 1;
+            }
+
+            route(&mut state, &method, &target, &body)
+        };
+
+        if !target.starts_with("/oauth2/") {
+            let bearer = authorization
+                .as_deref()
+                .and_then(|e| e.strip_prefix("Bearer "))
+                .unwrap_or_default()
+                .to_owned();
+            let request = format!("{} {}", method, target);
+            state.exchanges.push((request, bearer, response.status));
+        }
+
+        response
+    };
+
+    // Requests already sent with the old token fail while a refresh is in flight
+    if target == "/oauth2/token" {
+        sleep(TOKEN_DELAY).await;
+    }
+
+    respond(reader.into_inner(), response).await
+}
+
+async fn respond(mut stream: TcpStream, response: Response) -> std::io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// The authorize, device code and token endpoints.
+fn oauth_route(state: &mut State, method: &str, target: &str, body: &[u8]) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params: HashMap<String, String> = match method {
+        "GET" => form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect(),
+        _ => form_urlencoded::parse(body).into_owned().collect(),
+    };
+    let param = |name: &str| params.get(name).cloned().unwrap_or_default();
+
+    if param("client_id") != CLIENT_ID {
+        return oauth_error("invalid_client");
+    }
+
+    match (method, path) {
+        ("GET", "/oauth2/authorize") => {
+            if param("code_challenge_method") != "S256" {
+                return oauth_error("invalid_request");
+            }
+
+            let redirect_uri = param("redirect_uri");
+            state.oauth.challenge = Some(param("code_challenge"));
+            state.oauth.redirect_uri = Some(redirect_uri.clone());
+            let mut response = Response::status(302);
+            response.headers.push((
+                "Location",
+                format!(
+                    "{}?code={}&state={}",
+                    redirect_uri,
+                    AUTHORIZATION_CODE,
+                    param("state")
+                ),
+            ));
+            response
+        }
+        ("POST", "/oauth2/devicecode") => Response::json(&json!({
+            "device_code": DEVICE_CODE,
+            "user_code": "MOCK-CODE",
+            "verification_uri": "https://example.com/devicelogin",
+            "expires_in": 60,
+            "interval": 1,
+        })),
+        ("POST", "/oauth2/token") => match param("grant_type").as_str() {
+            "authorization_code" => {
+                let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(param("code_verifier")));
+
+                if param("code") != AUTHORIZATION_CODE
+                    || state.oauth.challenge.as_deref() != Some(challenge.as_str())
+                    || state.oauth.redirect_uri.as_deref() != Some(param("redirect_uri").as_str())
+                {
+                    return oauth_error("invalid_grant");
+                }
+
+                state.oauth.challenge = None;
+                issue_token(state)
+            }
+            "urn:ietf:params:oauth:grant-type:device_code" => {
+                state.oauth.polls += 1;
+
+                if param("device_code") != DEVICE_CODE {
+                    return oauth_error("invalid_grant");
+                }
+
+                if state.oauth.pending > 0 {
+                    state.oauth.pending -= 1;
+                    return oauth_error("authorization_pending");
+                }
+
+                issue_token(state)
+            }
+            "refresh_token" => {
+                if param("refresh_token") != REFRESH_TOKEN {
+                    return oauth_error("invalid_grant");
+                }
+
+                state.oauth.refreshes += 1;
+                issue_token(state)
+            }
+            _ => oauth_error("unsupported_grant_type"),
+        },
+        _ => Response::status(404),
+    }
+}
+
+/// A new access token, which replaces the one the API accepts.
+fn issue_token(state: &mut State) -> Response {
+    state.oauth.issued += 1;
+    state.access_token = format!("{}-{}", TOKEN, state.oauth.issued);
+    Response::json(&json!({
+        "access_token": state.access_token,
+        "expires_in": 3600,
+        "refresh_token": REFRESH_TOKEN,
+    }))
+}
+
+fn oauth_error(error: &str) -> Response {
+    let mut response = Response::json(&json!({
+        "error": error,
+        "error_description": format!("Mock {}", error),
+    }));
+    response.status = 400;
+    response
+}
+
+fn route(state: &mut State, method: &str, target: &str, body: &[u8]) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = match path.strip_prefix("/api/v1/") {
+        Some(it) => it,
+        None => return Response::status(404),
+    };
+    let query: HashMap<&str, &str> = query.split('&').filter_map(|e| e.split_once('=')).collect();
+    let param = |name: &str| query.get(name).and_then(|e| e.parse::<u64>().ok());
+    let segments: Vec<&str> = path.trim_end_matches(".json").split('/').collect();
+
+    match (method, segments.as_slice()) {
+        ("GET", ["users", "by_email"]) => {
+            let email = query
+                .get("email")
+                .map(|e| urlencoding::decode(e).unwrap().into_owned())
+                .unwrap_or_default();
+            let users: Vec<&ApiUser> = state
+                .users
+                .iter()
+                .filter(|e| e.email.as_deref() == Some(email.as_str()))
+                .collect();
+
+            if users.is_empty() {
+                return Response::status(404);
+            }
+
+            Response::json(&users)
+        }
+        ("GET", ["users"]) => {
+            let page = param("page").unwrap_or(1).max(1) as usize;
+            let per_page = param("num_per_page").unwrap_or(50) as usize;
+            let users: Vec<&ApiUser> = state
+                .users
+                .iter()
+                .skip((page - 1) * per_page)
+                .take(per_page)
+                .collect();
+            Response::json(&users)
+        }
+        ("GET", ["users", "current"]) => {
+            let user = state.current_user;
+
+            match state.users.iter().find(|e| e.id == user) {
+                Some(user) => Response::json(user),
+                None => Response::status(404),
+            }
+        }
+        ("GET", ["users", id]) => match state.users.iter().find(|e| id.parse() == Ok(e.id)) {
+            Some(user) => Response::json(user),
+            None => Response::status(404),
+        },
+        ("GET", ["groups", "for_user", id]) => {
+            let ids = id
+                .parse()
+                .ok()
+                .and_then(|e| state.members.get(&e))
+                .cloned()
+                .unwrap_or_default();
+            let groups: Vec<&ApiGroup> = state
+                .groups
+                .iter()
+                .filter(|e| ids.contains(&e.id))
+                .collect();
+            Response::json(&groups)
+        }
+        ("GET", ["messages", "sent"]) => {
+            let user = state.current_user;
+            threads(state, |e| e.sender_id == Some(user), param("older_than"))
+        }
+        ("GET", ["messages", "in_group", id]) => {
+            let group = id.parse().ok();
+            threads(state, |e| e.group_id == group, param("older_than"))
+        }
+        ("GET", ["messages"]) => threads(state, |_| true, param("older_than")),
+        ("GET", ["messages", "in_thread", id]) => {
+            let thread = id.parse().ok();
+            let mut messages: Vec<&ApiMessage> = state
+                .messages
+                .iter()
+                .filter(|e| e.thread_id == thread)
+                .collect();
+
+            if messages.is_empty() {
+                return Response::status(404);
+            }
+
+            messages.sort_by_key(|e| Reverse(e.id));
+            Response::json(&json!({
+                "messages": messages,
+                "meta": { "older_available": false },
+                "references": [],
+            }))
+        }
+        ("GET", ["search"]) => {
+            let text = query
+                .get("search")
+                .map(|e| urlencoding::decode(e).unwrap().to_lowercase())
+                .unwrap_or_default();
+            let matches = |value: &Option<String>| {
+                value
+                    .as_deref()
+                    .map(|e| e.to_lowercase().contains(&text))
+                    .unwrap_or(false)
+            };
+            let page = param("page").unwrap_or(1).max(1) as usize;
+            let messages: Vec<&ApiMessage> = state
+                .messages
+                .iter()
+                .filter(|e| matches(&e.body.as_ref().and_then(|e| e.plain.clone())))
+                .collect();
+            let users: Vec<&ApiUser> = state
+                .users
+                .iter()
+                .filter(|e| matches(&e.full_name) || matches(&e.email))
+                .collect();
+            let groups: Vec<&ApiGroup> = state.groups.iter().filter(|e| matches(&e.name)).collect();
+            let messages_page = search_page(&messages, page);
+            let references: Vec<Value> = messages_page
+                .iter()
+                .filter_map(|e| state.users.iter().find(|u| Some(u.id) == e.sender_id))
+                .map(|e| json!({ "type": "user", "id": e.id, "full_name": e.full_name, "email": e.email }))
+                .chain(
+                    messages_page
+                        .iter()
+                        .filter_map(|e| state.groups.iter().find(|g| Some(g.id) == e.group_id))
+                        .map(|e| json!({ "type": "group", "id": e.id, "name": e.name, "full_name": e.full_name })),
+                )
+                .collect();
+            Response::json(&json!({
+                "count": {
+                    "messages": messages.len(),
+                    "users": users.len(),
+                    "groups": groups.len(),
+                    "topics": 0,
+                },
+                "messages": { "messages": messages_page, "references": references },
+                "users": search_page(&users, page),
+                "groups": search_page(&groups, page),
+                "topics": [],
+            }))
+        }
+        ("GET", ["messages", "liked_by", id]) => {
+            let user = id.parse().ok();
+            page(
+                state,
+                |e| {
+                    e.liked_by
+                        .as_ref()
+                        .map(|e| e.names.iter().any(|e| e.user_id == user))
+                        .unwrap_or(false)
+                },
+                param("older_than"),
+            )
+        }
+        (method @ ("POST" | "DELETE"), ["messages", "liked_by", "current"]) => {
+            let user = state.current_user;
+            let message =
+                param("message_id").and_then(|id| state.messages.iter_mut().find(|e| e.id == id));
+            let liked_by = match message {
+                Some(it) => it.liked_by.get_or_insert_with(Default::default),
+                None => return Response::status(404),
+            };
+            liked_by.names.retain(|e| e.user_id != Some(user));
+
+            if method == "POST" {
+                liked_by.names.push(ApiLiker {
+                    user_id: Some(user),
+                    full_name: Some(format!("User {}", user)),
+                    ..Default::default()
+                });
+            }
+
+            liked_by.count = liked_by.names.len() as u64;
+            Response::status(200)
+        }
+        ("GET", ["messages", id]) => match state.messages.iter().find(|e| id.parse() == Ok(e.id)) {
+            Some(message) => Response::json(message),
+            None => Response::status(404),
+        },
+        ("POST", ["messages"]) => match serde_json::from_slice(body) {
+            Ok(body) => state.post(&body),
+            Err(_) => Response::status(400),
+        },
+        ("PUT", ["messages", id]) => {
+            let body: Value = serde_json::from_slice(body).unwrap_or_default();
+            let text = body["body"].as_str().unwrap_or_default().to_owned();
+            let protected = id.parse().map(|e| state.protected.contains(&e));
+
+            if protected == Ok(true) {
+                return Response::status(403);
+            }
+
+            match state.messages.iter_mut().find(|e| id.parse() == Ok(e.id)) {
+                Some(message) if !text.is_empty() => {
+                    message.body = Some(ApiBody {
+                        plain: Some(text),
+                        ..Default::default()
+                    });
+                    Response::status(200)
+                }
+                Some(_) => Response::status(400),
+                None => Response::status(404),
+            }
+        }
+        ("DELETE", ["messages", id]) => {
+            let id: u64 = match id.parse() {
+                Ok(it) => it,
+                Err(_) => return Response::status(404),
+            };
+
+            if state.protected.contains(&id) {
+                return Response::status(403);
+            }
+
+            match state.messages.iter().position(|e| e.id == id) {
+                Some(index) => {
+                    state.messages.remove(index);
+                    state.deleted.push(id);
+                    Response::status(200)
+                }
+                None => Response::status(404),
+            }
+        }
+        _ => Response::status(404),
+    }
+}
+
+/// A page of the `threaded=true` message lists: the first messages of the
+/// threads matching `filter` and older than `older_than`, newest first.
+fn threads<F>(state: &State, filter: F, older_than: Option<u64>) -> Response
+where
+    F: Fn(&ApiMessage) -> bool,
+{
+    page(
+        state,
+        |e| e.replied_to_id.is_none() && filter(e),
+        older_than,
+    )
+}
+
+/// The items of a 1-based search page.
+fn search_page<'a, T>(items: &[&'a T], page: usize) -> Vec<&'a T> {
+    items
+        .iter()
+        .skip((page - 1) * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .copied()
+        .collect()
+}
+
+/// A page of the messages matching `filter` and older than `older_than`,
+/// newest first.
+fn page<F>(state: &State, filter: F, older_than: Option<u64>) -> Response
+where
+    F: Fn(&ApiMessage) -> bool,
+{
+    let mut messages: Vec<&ApiMessage> = state
+        .messages
+        .iter()
+        .filter(|e| filter(e))
+        .filter(|e| older_than.map(|o| e.id < o).unwrap_or(true))
+        .collect();
+    messages.sort_by_key(|e| Reverse(e.id));
+    let older_available = messages.len() > PAGE_SIZE;
+    messages.truncate(PAGE_SIZE);
+    Response::json(&json!({
+        "messages": messages,
+        "meta": { "older_available": older_available },
+        "references": [],
+    }))
+}